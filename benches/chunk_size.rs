@@ -0,0 +1,33 @@
+//! Compare peak memory when importing a file with lots of rows into Cozo all
+//! at once and in chunks (`--chunk-size`.) Set `TREE_DB_BENCH_MB` to change
+//! how big the file is (the default is 2, which is about two million nodes.)
+
+mod common;
+
+fn main() {
+    let megabytes: usize = std::env::var("TREE_DB_BENCH_MB")
+        .ok()
+        .map(|mb| mb.parse().expect("TREE_DB_BENCH_MB should be a number"))
+        .unwrap_or(2);
+
+    // one node per byte or so, so the import is as big as it gets for the
+    // size of the file.
+    let mut source = String::from("[");
+    while source.len() < megabytes * 1024 * 1024 {
+        source.push_str("1,");
+    }
+    source.push_str("1]\n");
+
+    let bench = common::Bench::setup();
+    let input = bench.input("many-nodes.json", source.as_bytes());
+
+    println!("{megabytes} MiB of JSON");
+    bench.run("all at once", &input, &[]);
+    for chunk_size in ["100000", "10000"] {
+        bench.run(
+            &format!("--chunk-size {chunk_size}"),
+            &input,
+            &["--chunk-size", chunk_size],
+        );
+    }
+}
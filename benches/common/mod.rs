@@ -0,0 +1,125 @@
+//! Shared setup for the benchmarks. These run the real `tree-db` binary (it's
+//! a binary crate, so there's no library for a bench to call into) against
+//! generated JSON, and report wall time and peak memory for each set of
+//! arguments. Run them with `cargo bench --bench {name}`.
+//!
+//! We compile the JSON grammar from the `tree-sitter-json` dev-dependency's
+//! source on the way in, so all you need is a C compiler.
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// How many times to run each set of arguments. We report the fastest run
+/// and the most memory any run used.
+static RUNS: usize = 3;
+
+pub struct Bench {
+    dir: tempfile::TempDir,
+}
+
+impl Bench {
+    pub fn setup() -> Self {
+        let dir = tempfile::tempdir().expect("could not make a temporary directory");
+
+        let status = Command::new(env!("CARGO_BIN_EXE_tree-db"))
+            .arg("compile-grammar")
+            .arg("json")
+            .arg(tree_sitter_json_source())
+            .arg("--out-dir")
+            .arg(dir.path())
+            .status()
+            .expect("could not run tree-db compile-grammar");
+        assert!(status.success(), "could not compile the JSON grammar");
+
+        Self { dir }
+    }
+
+    /// Write `contents` to a file named `name` for the benchmark to parse.
+    pub fn input(&self, name: &str, contents: &[u8]) -> PathBuf {
+        let path = self.dir.path().join(name);
+        std::fs::write(&path, contents).expect("could not write benchmark input");
+        path
+    }
+
+    /// Export `input` as `cozo-json` with `args`, and print how long it took
+    /// and how much memory it used.
+    pub fn run(&self, label: &str, input: &Path, args: &[&str]) {
+        let mut fastest = Duration::MAX;
+        let mut peak_kib = 0;
+
+        for _ in 0..RUNS {
+            let start = Instant::now();
+            let child = Command::new(env!("CARGO_BIN_EXE_tree-db"))
+                .arg("export")
+                .args(["--output", "cozo-json", "--include"])
+                .arg(self.dir.path())
+                .args(args)
+                .arg(input)
+                .stdout(Stdio::null())
+                .spawn()
+                .expect("could not run tree-db export");
+
+            let (success, max_rss_kib) = wait(child);
+            assert!(success, "tree-db export {args:?} failed");
+
+            fastest = fastest.min(start.elapsed());
+            peak_kib = peak_kib.max(max_rss_kib);
+        }
+
+        println!(
+            "{label:<24} {:>8.2}s {:>8.1} MiB peak",
+            fastest.as_secs_f64(),
+            peak_kib as f64 / 1024.0
+        );
+    }
+}
+
+/// Wait for `child` to exit, returning whether it succeeded and its peak
+/// resident memory in KiB. `Child::wait` doesn't give us the latter, so we
+/// reap it ourselves.
+fn wait(child: std::process::Child) -> (bool, i64) {
+    let mut status = 0;
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+
+    let pid = unsafe { libc::wait4(child.id() as libc::pid_t, &mut status, 0, &mut usage) };
+    assert!(pid > 0, "could not wait for tree-db");
+
+    // Linux reports this in KiB, but macOS reports bytes.
+    let max_rss_kib = if cfg!(target_os = "macos") {
+        usage.ru_maxrss / 1024
+    } else {
+        usage.ru_maxrss
+    };
+
+    (
+        libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0,
+        max_rss_kib,
+    )
+}
+
+/// Where cargo put the source for our `tree-sitter-json` dev-dependency.
+fn tree_sitter_json_source() -> PathBuf {
+    let output = Command::new(env!("CARGO"))
+        .args(["metadata", "--format-version", "1", "--manifest-path"])
+        .arg(Path::new(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml"))
+        .output()
+        .expect("could not run cargo metadata");
+    assert!(output.status.success(), "cargo metadata failed");
+
+    let metadata: serde_json::Value =
+        serde_json::from_slice(&output.stdout).expect("cargo metadata should output JSON");
+
+    let manifest = metadata["packages"]
+        .as_array()
+        .expect("cargo metadata should list packages")
+        .iter()
+        .find(|package| package["name"] == "tree-sitter-json")
+        .and_then(|package| package["manifest_path"].as_str())
+        .expect("tree-sitter-json should be a dev-dependency");
+
+    Path::new(manifest)
+        .parent()
+        .expect("a manifest should be in a directory")
+        .to_path_buf()
+}
@@ -0,0 +1,28 @@
+//! Compare reading source files into memory with mapping them (`--mmap`) on
+//! one large file. Set `TREE_DB_BENCH_MB` to change how big it is (the
+//! default is 64.)
+
+mod common;
+
+fn main() {
+    let megabytes: usize = std::env::var("TREE_DB_BENCH_MB")
+        .ok()
+        .map(|mb| mb.parse().expect("TREE_DB_BENCH_MB should be a number"))
+        .unwrap_or(64);
+
+    // mostly long strings, so the file is big compared to the number of
+    // nodes we have to keep for it.
+    let line = format!("  {{\"key\": \"{}\"}},\n", "value ".repeat(64));
+    let mut source = String::from("[\n");
+    while source.len() < megabytes * 1024 * 1024 {
+        source.push_str(&line);
+    }
+    source.push_str("  {}\n]\n");
+
+    let bench = common::Bench::setup();
+    let input = bench.input("large.json", source.as_bytes());
+
+    println!("{megabytes} MiB of JSON");
+    bench.run("read", &input, &[]);
+    bench.run("--mmap", &input, &["--mmap"]);
+}
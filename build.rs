@@ -0,0 +1,77 @@
+use serde_json::Value;
+use std::process::Command;
+
+/// Make the versions of the libraries we link against available to `tree-db
+/// --version`, since neither tree-sitter nor Cozo exposes its own. We ask
+/// Cargo which versions it resolved for us rather than guessing, and fail
+/// the build if it can't tell us.
+fn main() {
+    println!("cargo:rerun-if-changed=Cargo.toml");
+    println!("cargo:rerun-if-changed=Cargo.lock");
+
+    let output = Command::new(std::env::var("CARGO").expect("cargo sets CARGO for build scripts"))
+        .args([
+            "metadata",
+            "--format-version",
+            "1",
+            "--offline",
+            "--manifest-path",
+        ])
+        .arg(
+            std::path::Path::new(
+                &std::env::var("CARGO_MANIFEST_DIR")
+                    .expect("cargo sets CARGO_MANIFEST_DIR for build scripts"),
+            )
+            .join("Cargo.toml"),
+        )
+        .output()
+        .expect("could not run `cargo metadata`");
+    if !output.status.success() {
+        panic!(
+            "`cargo metadata` failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    let metadata: Value =
+        serde_json::from_slice(&output.stdout).expect("`cargo metadata` should output JSON");
+
+    for (package, var) in [
+        ("cozo", "TREE_DB_COZO_VERSION"),
+        ("tree-sitter", "TREE_DB_TREE_SITTER_VERSION"),
+    ] {
+        match resolved_version(&metadata, package) {
+            Some(version) => println!("cargo:rustc-env={var}={version}"),
+            None => panic!("could not find the version of `{package}` we depend on"),
+        }
+    }
+}
+
+/// The version of `package` that our own dependency on it resolved to.
+/// There might be other versions in the dependency graph (through our
+/// dependencies' dependencies), so we go through the resolve graph instead
+/// of looking for the first package with the right name.
+fn resolved_version<'metadata>(
+    metadata: &'metadata Value,
+    package: &str,
+) -> Option<&'metadata str> {
+    let root = metadata["resolve"]["root"].as_str()?;
+    let root_node = metadata["resolve"]["nodes"]
+        .as_array()?
+        .iter()
+        .find(|node| node["id"] == root)?;
+
+    // `deps` uses the name as it appears in Rust code, with underscores
+    let dep_name = package.replace('-', "_");
+    let id = root_node["deps"]
+        .as_array()?
+        .iter()
+        .find(|dep| dep["name"] == dep_name.as_str())?["pkg"]
+        .as_str()?;
+
+    metadata["packages"]
+        .as_array()?
+        .iter()
+        .find(|candidate| candidate["id"] == id)?["version"]
+        .as_str()
+}
@@ -0,0 +1,70 @@
+use tree_sitter::{InputEdit, Point, Tree};
+
+/// A parsed file to use as the starting point for parsing similar files
+/// (like generated variants of the same template), for
+/// `--incremental-baseline`.
+///
+/// tree-sitter's incremental parsing assumes the old tree matches the new
+/// source everywhere that hasn't been edited, so we can't just hand it the
+/// baseline's tree. Instead, we treat each file as one big edit of the
+/// baseline: everything between their common prefix and common suffix was
+/// replaced. The more of the file that's shared, the more of the old tree
+/// tree-sitter can reuse.
+#[derive(Debug)]
+pub struct Baseline {
+    pub language: String,
+    source: Vec<u8>,
+    tree: Tree,
+}
+
+impl Baseline {
+    pub fn new(language: String, source: Vec<u8>, tree: Tree) -> Self {
+        Self {
+            language,
+            source,
+            tree,
+        }
+    }
+
+    /// A copy of the baseline's tree, edited to line up with `new`.
+    pub fn tree_for(&self, new: &[u8]) -> Tree {
+        let old = &self.source[..];
+
+        let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+
+        // don't let the suffix overlap the prefix in either file
+        let max_suffix = old.len().min(new.len()) - prefix;
+        let suffix = old
+            .iter()
+            .rev()
+            .zip(new.iter().rev())
+            .take(max_suffix)
+            .take_while(|(a, b)| a == b)
+            .count();
+
+        let mut tree = self.tree.clone();
+        tree.edit(&InputEdit {
+            start_byte: prefix,
+            old_end_byte: old.len() - suffix,
+            new_end_byte: new.len() - suffix,
+            start_position: point_at(old, prefix),
+            old_end_position: point_at(old, old.len() - suffix),
+            new_end_position: point_at(new, new.len() - suffix),
+        });
+
+        tree
+    }
+}
+
+/// The row and column of a byte offset, the way tree-sitter counts them
+/// (columns are in bytes.)
+fn point_at(bytes: &[u8], offset: usize) -> Point {
+    let before = &bytes[..offset];
+    match before.iter().rposition(|byte| *byte == b'\n') {
+        Some(newline) => Point::new(
+            before.iter().filter(|byte| **byte == b'\n').count(),
+            offset - newline - 1,
+        ),
+        None => Point::new(0, offset),
+    }
+}
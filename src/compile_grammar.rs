@@ -0,0 +1,256 @@
+use crate::loader::{Loader, DYLIB_EXTENSION};
+use crate::manifest::{self, Manifest};
+use color_eyre::eyre::{bail, eyre, Result, WrapErr};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tracing::instrument;
+
+#[derive(Debug, clap::Parser)]
+pub struct CompileGrammarConfig {
+    /// The name of the language. This has to match the grammar's
+    /// `tree_sitter_{name}` symbol, and is what we'll look for when loading.
+    name: String,
+
+    /// The root of the grammar's source (the directory containing `src/parser.c`
+    /// or `grammar.js`.)
+    path: PathBuf,
+
+    /// Where to put the compiled library. Defaults to the first directory in
+    /// `TREE_DB_LANGUAGE_SEARCH_PATH` (which is where `export` and `query`
+    /// look for grammars) if it's set, or `.` (the default include path) if
+    /// not. That way compiling and loading agree on where grammars live.
+    /// We'll create it if it doesn't exist yet.
+    #[arg(long)]
+    out_dir: Option<PathBuf>,
+
+    /// If the grammar only has a `grammar.js`, run `tree-sitter generate` to
+    /// make `src/parser.c` before compiling.
+    #[arg(long)]
+    generate: bool,
+
+    /// The `tree-sitter` CLI to use with `--generate`.
+    #[arg(long, default_value = "tree-sitter", env = "TREE_DB_TREE_SITTER_CLI")]
+    tree_sitter_cli: PathBuf,
+
+    /// Show the C compiler's warnings. Generated parsers are noisy, so these
+    /// are off by default, but they're useful when you're writing a scanner.
+    #[arg(long)]
+    warnings: bool,
+
+    /// Treat compiler warnings as errors. Implies --warnings.
+    #[arg(long)]
+    werror: bool,
+
+    /// The C++ standard to compile external scanners written in C++
+    /// (`src/scanner.cc`) with, as passed to `-std=`. Some scanners use
+    /// newer features and won't compile without it. Grammars written only
+    /// in C don't get this flag.
+    #[arg(long, default_value = "c++14")]
+    cxx_std: String,
+
+    /// Record what we built in this JSON manifest: the grammar's name,
+    /// source and library paths, ABI version, and a hash of the library. If
+    /// the manifest already exists, we add to it (replacing any earlier
+    /// entry for the same grammar), so one manifest can cover every grammar
+    /// a project uses.
+    #[arg(long)]
+    manifest: Option<PathBuf>,
+}
+
+impl CompileGrammarConfig {
+    #[instrument]
+    pub fn run(&self) -> Result<()> {
+        let src = self.path.join("src");
+        let parser = src.join("parser.c");
+
+        if !parser.exists() {
+            if !self.path.join("grammar.js").exists() {
+                bail!(
+                    "could not find `{}` or a `grammar.js` to generate it from",
+                    parser.display()
+                );
+            } else if !self.generate {
+                bail!(
+                    "`{}` doesn't exist, but there's a `grammar.js`. Pass `--generate` to generate it with the tree-sitter CLI.",
+                    parser.display()
+                );
+            }
+
+            self.generate_parser()
+                .wrap_err("could not generate parser from `grammar.js`")?;
+        }
+
+        let host = guess_host_triple::guess_host_triple()
+            .ok_or_else(|| eyre!("could not guess the host triple for this machine"))?;
+
+        let mut build = cc::Build::new();
+        build
+            .cargo_metadata(false)
+            .debug(false)
+            .opt_level(2)
+            .host(host)
+            .target(host)
+            .warnings(self.warnings || self.werror)
+            .warnings_into_errors(self.werror)
+            .include(&src);
+
+        let mut sources = vec![parser];
+
+        let scanner_c = src.join("scanner.c");
+        let scanner_cc = src.join("scanner.cc");
+        if scanner_c.exists() {
+            sources.push(scanner_c);
+        } else if scanner_cc.exists() {
+            build.cpp(true).flag(&format!("-std={}", self.cxx_std));
+            sources.push(scanner_cc);
+        }
+
+        // otherwise the compiler fails with a confusing linker error
+        let out_dir = self.out_dir();
+        std::fs::create_dir_all(&out_dir)
+            .wrap_err_with(|| format!("could not create `{}`", out_dir.display()))?;
+
+        let out = self.out_path();
+
+        let mut command = build
+            .try_get_compiler()
+            .wrap_err("could not find a C compiler")?
+            .to_command();
+        command
+            .arg("-shared")
+            .arg("-fno-exceptions")
+            .args(&sources)
+            .arg("-o")
+            .arg(&out);
+
+        tracing::debug!(?command, "compiling grammar");
+        let status = command.status().wrap_err("could not run the C compiler")?;
+        if !status.success() {
+            bail!("compiling `{}` failed ({status})", out.display());
+        }
+
+        tracing::info!(out = ?out, "compiled grammar");
+
+        if let Some(path) = &self.manifest {
+            self.record(path, &out)
+                .wrap_err_with(|| format!("could not update manifest `{}`", path.display()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Add the grammar we just built to the manifest at `path`. Loading it
+    /// to get the ABI version also makes sure it has the symbol we expect.
+    fn record(&self, path: &Path, out: &Path) -> Result<()> {
+        let mut loader = Loader::with_capacity(Vec::new(), 1);
+        loader.pin(self.name.clone(), out.to_path_buf());
+        loader
+            .preload(self.name.clone())
+            .wrap_err("could not load the grammar we just built")?;
+        let language = loader
+            .get(&self.name)
+            .ok_or_else(|| eyre!("internal error: the grammar should have been loaded"))?;
+
+        let absolute = |path: &Path| {
+            path.canonicalize()
+                .wrap_err_with(|| format!("could not canonicalize `{}`", path.display()))
+        };
+
+        let mut manifest = Manifest::load_or_default(path)?;
+        manifest.grammars.insert(
+            self.name.clone(),
+            manifest::Grammar {
+                source: absolute(&self.path)?,
+                library: absolute(out)?,
+                symbol: format!("tree_sitter_{}", self.name),
+                abi_version: language.version(),
+                hash: manifest::hash_file(out)?,
+            },
+        );
+        manifest.save(path)
+    }
+
+    fn out_dir(&self) -> PathBuf {
+        if let Some(out_dir) = &self.out_dir {
+            return out_dir.clone();
+        }
+
+        std::env::var_os("TREE_DB_LANGUAGE_SEARCH_PATH")
+            .and_then(|paths| {
+                std::env::split_paths(&paths).find(|path| !path.as_os_str().is_empty())
+            })
+            .unwrap_or_else(|| PathBuf::from("."))
+    }
+
+    fn out_path(&self) -> PathBuf {
+        self.out_dir()
+            .join(format!("tree-sitter-{}.{}", self.name, DYLIB_EXTENSION))
+    }
+
+    fn generate_parser(&self) -> Result<()> {
+        tracing::info!(path = ?self.path, "generating parser");
+
+        let status = match Command::new(&self.tree_sitter_cli)
+            .arg("generate")
+            .current_dir(&self.path)
+            .status()
+        {
+            Ok(status) => status,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => bail!(
+                "could not find the tree-sitter CLI at `{}`. Install it or point to it with `--tree-sitter-cli`.",
+                self.tree_sitter_cli.display()
+            ),
+            Err(err) => return Err(err).wrap_err("could not run the tree-sitter CLI"),
+        };
+
+        if !status.success() {
+            bail!("`tree-sitter generate` failed ({status})");
+        }
+
+        if !self.path.join("src").join("parser.c").exists() {
+            bail!("`tree-sitter generate` succeeded, but didn't make `src/parser.c`");
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser as _;
+    use std::ffi::OsStr;
+
+    #[test]
+    fn compiling_creates_the_out_dir() {
+        let dir = tempfile::Builder::new()
+            .prefix("tree-db")
+            .tempdir()
+            .unwrap();
+
+        // we only need something the compiler can build into a library, not
+        // a real grammar (we don't load it without --manifest.)
+        let grammar = dir.path().join("grammar");
+        std::fs::create_dir_all(grammar.join("src")).unwrap();
+        std::fs::write(
+            grammar.join("src").join("parser.c"),
+            "int tree_sitter_fake(void) { return 0; }\n",
+        )
+        .unwrap();
+
+        let out_dir = dir.path().join("not").join("there").join("yet");
+        let config = CompileGrammarConfig::try_parse_from([
+            OsStr::new("compile-grammar"),
+            OsStr::new("fake"),
+            grammar.as_os_str(),
+            OsStr::new("--out-dir"),
+            out_dir.as_os_str(),
+        ])
+        .unwrap();
+
+        config.run().unwrap();
+
+        assert!(out_dir.is_dir());
+        assert!(config.out_path().is_file());
+    }
+}
@@ -0,0 +1,29 @@
+use color_eyre::eyre::{eyre, Report};
+use miette::{GraphicalReportHandler, GraphicalTheme};
+
+/// Cozo reports errors with miette, which doesn't compose with color_eyre.
+/// This renders them the way miette would (the message, any help, and the
+/// spans of the script they point to) and wraps that up in a report, so
+/// Cozo errors read like all our other errors instead of a debug dump.
+pub trait IntoReport<T> {
+    fn into_report(self) -> color_eyre::Result<T>;
+}
+
+impl<T> IntoReport<T> for Result<T, cozo::Error> {
+    fn into_report(self) -> color_eyre::Result<T> {
+        self.map_err(convert)
+    }
+}
+
+fn convert(err: cozo::Error) -> Report {
+    let mut rendered = String::new();
+
+    match GraphicalReportHandler::new_themed(GraphicalTheme::unicode_nocolor())
+        .render_report(&mut rendered, &*err)
+    {
+        Ok(()) => eyre!("{}", rendered.trim_end()),
+        // if rendering fails for some reason, the plain message is still a
+        // lot better than nothing.
+        Err(_) => eyre!("{err}"),
+    }
+}
@@ -0,0 +1,105 @@
+use crate::export::{ExporterConfig, OutputWriter};
+use color_eyre::eyre::{Result, WrapErr};
+use cozo::NamedRows;
+use serde_json::json;
+use serde_json::value::Value;
+use std::collections::BTreeMap;
+use std::path::Path;
+use tracing::instrument;
+
+/// Writes a CSV per relation into the -o/--output-path directory, or one
+/// combined `tree-db.csv` with --csv-combined.
+pub struct CsvWriter;
+
+impl OutputWriter for CsvWriter {
+    fn write_relations(
+        &self,
+        relations: &BTreeMap<String, NamedRows>,
+        cfg: &ExporterConfig,
+    ) -> Result<()> {
+        let output_path = cfg.output_dir("CSV")?;
+
+        if cfg.csv_combined {
+            return write_csv(
+                &output_path.join("tree-db.csv"),
+                &combine(&cfg.source.relation_names(), relations),
+                cfg,
+            )
+            .wrap_err("could not export `tree-db.csv`");
+        }
+
+        for name in cfg.source.relation_names() {
+            write_csv(
+                &output_path.join(format!("{name}.csv")),
+                relations
+                    .get(name)
+                    .expect("all relations should be present in the export"),
+                cfg,
+            )
+            .wrap_err_with(|| format!("could not export `{name}.csv`"))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Put all the rows from `relations` in one table, with a `relation`
+/// column first and then the union of all their columns.
+fn combine(names: &[&str], relations: &BTreeMap<String, NamedRows>) -> NamedRows {
+    let mut headers = vec!["relation".to_string()];
+    for name in names {
+        for header in &relations[*name].headers {
+            if !headers.contains(header) {
+                headers.push(header.clone());
+            }
+        }
+    }
+
+    let mut rows = Vec::new();
+    for name in names {
+        let data = &relations[*name];
+        let indexes: Vec<usize> = data
+            .headers
+            .iter()
+            .map(|header| {
+                headers
+                    .iter()
+                    .position(|combined| combined == header)
+                    .expect("every header should be in the combined headers")
+            })
+            .collect();
+
+        for row in &data.rows {
+            let mut combined = vec![Value::Null; headers.len()];
+            combined[0] = json!(name);
+            for (value, index) in row.iter().zip(&indexes) {
+                combined[*index] = value.clone();
+            }
+            rows.push(combined);
+        }
+    }
+
+    NamedRows { headers, rows }
+}
+
+#[instrument(skip(data, cfg))]
+fn write_csv(path: &Path, data: &NamedRows, cfg: &ExporterConfig) -> Result<()> {
+    let nodes_file = std::fs::File::create(path)?;
+
+    let mut csv_writer = csv::Writer::from_writer(nodes_file);
+    csv_writer
+        .write_record(&data.headers)
+        .wrap_err("could not write header")?;
+
+    for row in &data.rows {
+        csv_writer
+            .write_record(row.iter().map(|value| match value {
+                Value::Null => cfg.csv_null.clone(),
+                Value::String(s) => s.clone(),
+                other => other.to_string(),
+            }))
+            .wrap_err("could not write row")?;
+    }
+
+    Ok(())
+}
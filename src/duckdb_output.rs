@@ -0,0 +1,98 @@
+use crate::export::{ExporterConfig, OutputWriter};
+use color_eyre::eyre::{bail, Result};
+#[cfg(feature = "duckdb")]
+use color_eyre::eyre::{eyre, WrapErr};
+use cozo::NamedRows;
+#[cfg(feature = "duckdb")]
+use serde_json::value::Value;
+use std::collections::BTreeMap;
+#[cfg(feature = "duckdb")]
+use tracing::instrument;
+
+/// Writes a DuckDB database with a table for each relation. This is only
+/// available if tree-db was built with the `duckdb` feature.
+pub struct DuckDbWriter;
+
+static NO_DUCKDB: &str = "tree-db was built without DuckDB support. Rebuild it with `--features duckdb` to use the `duckdb` output.";
+
+impl DuckDbWriter {
+    /// Without the `duckdb` feature, this fails right away instead of after
+    /// we've parsed everything.
+    pub fn new() -> Result<Self> {
+        if cfg!(feature = "duckdb") {
+            Ok(Self)
+        } else {
+            bail!(NO_DUCKDB)
+        }
+    }
+}
+
+impl OutputWriter for DuckDbWriter {
+    #[cfg(feature = "duckdb")]
+    #[instrument(skip(self, relations, cfg))]
+    fn write_relations(
+        &self,
+        relations: &BTreeMap<String, NamedRows>,
+        cfg: &ExporterConfig,
+    ) -> Result<()> {
+        let path = cfg
+            .output_path
+            .as_ref()
+            .ok_or_else(|| eyre!("output_path is required, but should have been validated by clap. Is there a misconfiguration or bug?"))?;
+
+        // DuckDB would happily add to an existing database, but then the
+        // tables we create would already be there.
+        if path.exists() {
+            bail!(
+                "`{}` already exists. Remove it first if you want to replace it.",
+                path.display()
+            );
+        }
+
+        let conn = duckdb::Connection::open(path)
+            .wrap_err_with(|| format!("could not create `{}`", path.display()))?;
+
+        for relation in cfg.source.schema() {
+            conn.execute_batch(&relation.to_duckdb())
+                .wrap_err_with(|| format!("could not create `{}`", relation.name))?;
+
+            let data = relations
+                .get(relation.name)
+                .expect("all relations should be present in the export");
+
+            let mut appender = conn
+                .appender(relation.name)
+                .wrap_err_with(|| format!("could not load `{}`", relation.name))?;
+            for row in &data.rows {
+                appender
+                    .append_row(duckdb::params_from_iter(row.iter().map(duckdb_value)))
+                    .wrap_err_with(|| format!("could not load `{}`", relation.name))?;
+            }
+            appender
+                .flush()
+                .wrap_err_with(|| format!("could not load `{}`", relation.name))?;
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(feature = "duckdb"))]
+    fn write_relations(&self, _: &BTreeMap<String, NamedRows>, _: &ExporterConfig) -> Result<()> {
+        bail!(NO_DUCKDB)
+    }
+}
+
+/// We only ever export scalars, and only integers of the numbers.
+#[cfg(feature = "duckdb")]
+fn duckdb_value(value: &Value) -> duckdb::types::Value {
+    match value {
+        Value::Null => duckdb::types::Value::Null,
+        Value::Bool(b) => duckdb::types::Value::Boolean(*b),
+        Value::Number(n) => match n.as_i64() {
+            Some(n) => duckdb::types::Value::BigInt(n),
+            None => duckdb::types::Value::Text(n.to_string()),
+        },
+        Value::String(s) => duckdb::types::Value::Text(s.clone()),
+        other => duckdb::types::Value::Text(other.to_string()),
+    }
+}
@@ -0,0 +1,21 @@
+use crate::export::SourceConfig;
+use color_eyre::eyre::{Result, WrapErr};
+use std::io::Write;
+use tracing::instrument;
+
+#[derive(Debug, clap::Parser)]
+pub struct DumpAstConfig {
+    /// Which file to dump (plus all the usual options for how to export it.)
+    /// This has to match exactly one file.
+    #[command(flatten)]
+    source: SourceConfig,
+}
+
+impl DumpAstConfig {
+    #[instrument]
+    pub fn run(&self) -> Result<()> {
+        let dump = self.source.dump_ast().wrap_err("could not dump the tree")?;
+
+        write!(std::io::stdout(), "{dump}").wrap_err("could not write to stdout")
+    }
+}
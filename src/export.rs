@@ -1,21 +1,106 @@
+use crate::baseline::Baseline;
+use crate::cozo_error::IntoReport;
+use crate::csv_output::CsvWriter;
+use crate::duckdb_output::DuckDbWriter;
+use crate::file_args::{self, FileArgs};
+use crate::git;
+use crate::gzip;
+use crate::language_overrides::LanguageOverrides;
 use crate::loader::Loader;
+use crate::manifest::Manifest;
+use crate::mermaid::MermaidWriter;
+use crate::neo4j::{CypherWriter, Neo4jCsvWriter};
+use crate::parser_cache::{CachedFile, ParserCache};
+use crate::postgres_copy::PostgresCopyWriter;
+use crate::schema::{self, Column, ColumnType, Relation};
+use crate::shebang;
+use crate::stats::Stats;
+use crate::timings::Timings;
 use color_eyre::eyre::{bail, eyre, Result, WrapErr};
 use cozo::NamedRows;
+use is_terminal::IsTerminal;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
 use rayon::prelude::*;
 use serde_json::json;
 use serde_json::value::Value;
-use std::collections::{BTreeMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fmt::Debug;
-use std::io::{Read, Write};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
 use tracing::instrument;
-use tree_sitter::{Language, Node, Parser};
+use tree_sitter::{Language, Node, Parser, TreeCursor};
 
 #[derive(Debug, clap::Parser)]
 pub struct ExporterConfig {
-    /// What format do you want the output in?
-    output: Output,
+    /// What format do you want the output in? You can ask for several at once
+    /// by repeating this (like `--output cozo-json --output cozo-sqlite`) to
+    /// avoid parsing everything more than once. When you do, only one of them
+    /// can write to -o/--output-path, and only one of the rest can go to
+    /// stdout (since we'd have no way to tell where one ended and the next
+    /// began.)
+    ///
+    /// Required unless you're using --print-schema-for.
+    #[arg(long = "output", action = clap::ArgAction::Append)]
+    output: Vec<Output>,
 
+    /// Instead of exporting, print the schema we'd export with these options
+    /// in a form that suits some other tool, so you can set up tables before
+    /// loading CSVs into them.
+    #[arg(long)]
+    print_schema_for: Option<SchemaTarget>,
+
+    /// What to write in CSV output for missing values (like `source` on
+    /// nodes with children, or `field` on edges that don't have one.) By
+    /// default it's an empty cell, but some loaders can't tell that apart
+    /// from an empty string.
+    #[arg(long, default_value = "")]
+    pub csv_null: String,
+
+    /// Write CSV output as a single `tree-db.csv` instead of one file per
+    /// relation. It has a `relation` column saying which relation each row
+    /// is from, then every column from every relation (empty where a
+    /// relation doesn't have that column.)
+    #[arg(long)]
+    pub csv_combined: bool,
+
+    #[arg(
+        long,
+        short('o'),
+        required_if_eq("output", "cozo-sqlite"),
+        required_if_eq("output", "csv")
+    )]
+    pub output_path: Option<PathBuf>,
+
+    /// With `cozo-sqlite`, write one database per input file into the
+    /// directory at -o/--output-path instead of one for everything. We
+    /// create the directory if it's not there yet.
+    #[arg(long, requires = "output_path")]
+    sqlite_per_file: bool,
+
+    /// Export again every this long (like `30s` or `5m`) until interrupted
+    /// with Ctrl-C, overwriting the output each time. This keeps an index
+    /// fresh for a dashboard without a separate scheduler, and works where
+    /// filesystem events don't (like network mounts.) The interval is from
+    /// the start of one export to the start of the next, so an export that
+    /// takes longer than the interval is followed immediately by another.
+    /// With an on-disk --engine, use --resume too so that each export only
+    /// re-parses files that changed.
+    #[arg(long)]
+    interval: Option<humantime::Duration>,
+
+    #[command(flatten)]
+    pub source: SourceConfig,
+}
+
+/// Options for finding and parsing source files. These are shared between
+/// every command that needs to build a database from source.
+#[derive(Debug, clap::Args, serde::Serialize)]
+pub struct SourceConfig {
     /// Which languages should we include? (Defaults to all languages whose extensions we know.)
     #[arg(short('l'), long)]
     language: Vec<String>,
@@ -29,32 +114,103 @@ pub struct ExporterConfig {
     #[arg(long)]
     custom_language: Vec<String>,
 
+    /// A file of custom language definitions, one per line, in the same
+    /// format as --custom-language (`{name}:{glob}`, or `{name}:include:{other}`
+    /// to include another type's globs, like ripgrep's `--type-add`.) Blank
+    /// lines and lines starting with `#` are ignored.
+    #[arg(long)]
+    types_file: Option<PathBuf>,
+
+    /// Parse files of one language with another language's grammar, in the
+    /// format `{label}={grammar}` (for example `mjs=javascript`.) This saves
+    /// compiling the same grammar under several names. Aliases apply after
+    /// --custom-language, --types-file, and --language-overrides have
+    /// decided which language a file is, and the file is recorded with the
+    /// grammar's name.
+    #[arg(long)]
+    language_alias: Vec<LanguageAlias>,
+
+    /// A file of per-path language overrides, one `{name}:{glob}` per line.
+    /// These take precedence over extension-based detection for any file we
+    /// find, which is useful for ambiguous extensions (e.g. `.h` as C in one
+    /// directory and C++ in another.) Note that files still have to match
+    /// some known language to be found in the first place.
+    #[arg(long)]
+    language_overrides: Option<PathBuf>,
+
     /// Paths to look for language libraries. Use `tree-db compile-grammar` to
-    /// make these.
-    #[arg(
-        long,
-        short('i'),
-        default_value = ".",
-        env = "TREE_DB_LANGUAGE_SEARCH_PATH"
-    )]
+    /// make these. If you don't pass any, we'll use the directories in
+    /// `TREE_DB_LANGUAGE_SEARCH_PATH` (separated by `:`, or `;` on Windows,
+    /// like `PATH`), or `.` if that isn't set either.
+    #[arg(long, short('i'))]
     include: Vec<PathBuf>,
 
-    #[arg(
-        long,
-        short('o'),
-        required_if_eq("output", "cozo-sqlite"),
-        required_if_eq("output", "csv")
-    )]
-    output_path: Option<PathBuf>,
+    /// Load a language's grammar from exactly this library, in the format
+    /// `{name}={path}` (for example `python=/opt/grammars/python-0.20.so`),
+    /// instead of searching the include paths for it. Handy for pinning a
+    /// particular build, or comparing two versions of a grammar.
+    #[arg(long)]
+    grammar: Vec<GrammarPath>,
+
+    /// Load grammars from the paths in this manifest (as written by
+    /// `compile-grammar --manifest`) instead of searching the include paths,
+    /// checking each library's hash and ABI version against the manifest
+    /// first. Grammars the manifest doesn't list are searched for as usual,
+    /// and --grammar still takes precedence.
+    #[arg(long)]
+    grammar_manifest: Option<PathBuf>,
+
+    /// If a grammar isn't directly in any include path, look for it in their
+    /// subdirectories too (up to four levels deep), so you can point -i at
+    /// a single `grammars` directory however it's laid out inside.
+    #[arg(long)]
+    grammar_search_recursive: bool,
+
+    /// Resolve relative paths (in file arguments, --range, and
+    /// --incremental-baseline) against this directory instead of the working
+    /// directory, and store paths relative to it (files outside it are
+    /// stored by their absolute path.) Ignore files are found from here too.
+    /// This makes exports from wrapper scripts and CI come out the same no
+    /// matter where they're run from.
+    #[arg(long)]
+    root: Option<PathBuf>,
 
     /// Where to search for files. These can either be directories or files.
+    /// You can also use globs like `"src/**/*.rs"` (quoted so your shell
+    /// doesn't expand them.) Files matched by a glob are still subject to the
+    /// hidden and ignore rules below.
     #[arg(default_value = ".")]
     file: Vec<PathBuf>,
 
+    /// Store paths exactly as we found them instead of cleaning up `.` and
+    /// `..` (so `./src/main.rs` stays `./src/main.rs` instead of becoming
+    /// `src/main.rs`.)
+    ///
+    /// Also available as --no-canonicalize. Note that we only normalize
+    /// paths, rather than canonicalizing them like `realpath` does: symlinks
+    /// stay as they are, and relative paths stay relative, so that exports
+    /// from different checkouts of the same repo can be compared by path.
+    /// (We still canonicalize internally to find files given more than
+    /// once.)
+    #[arg(long, visible_alias = "no-canonicalize")]
+    no_normalize_paths: bool,
+
     /// Include hidden files
     #[arg(long)]
     no_hidden: bool,
 
+    /// Only parse files that git says are staged, modified, or new (and not
+    /// ignored.) Handy for pre-commit hooks. We ask the repo --root is in,
+    /// if it's set. Outside of a git repo, we'll warn and parse everything
+    /// as usual.
+    #[arg(long)]
+    git_changed: bool,
+
+    /// Don't try to figure out the language of files without an extension
+    /// (like `bin/build`) from their `#!` line.
+    #[arg(long)]
+    no_shebang: bool,
+
     /// Parse and use `.ignore` files
     #[arg(long)]
     no_ignore: bool,
@@ -62,394 +218,3612 @@ pub struct ExporterConfig {
     /// Parse and use ignore information from git
     #[arg(long)]
     no_git_ignore: bool,
-}
 
-#[derive(Debug, Clone, PartialEq, Eq, clap::ValueEnum)]
-pub enum Output {
-    /// Cozo relations, as JSON
-    CozoJson,
+    /// Only allow grammars whose ABI version is in this range, in the format
+    /// `{min}-{max}` (for example `13-14`.) Either side may be left blank to
+    /// leave it unbounded. Useful for making sure everyone on a team indexes
+    /// with compatible grammars.
+    #[arg(long)]
+    grammar_version_check: Option<GrammarVersionPolicy>,
 
-    /// The Cozo schema that we're assuming as a query you can run to start
-    /// your own Cozo database.
-    CozoSchema,
+    /// If a grammar is present but can't be loaded (for example if it's
+    /// corrupt or was built for another platform), skip files in that
+    /// language instead of stopping. We'll list the grammars we skipped at
+    /// the end.
+    #[arg(long)]
+    skip_broken_grammars: bool,
 
-    /// A SQLite database, as a file
-    CozoSqlite,
+    /// What to do when a grammar library looks like it's for a different
+    /// language than the one we loaded it as. We can't ask a grammar its
+    /// name, so we go by the library: if `tree-sitter-bar.so` is where we
+    /// found `foo`, and it exports `tree_sitter_bar` as well as
+    /// `tree_sitter_foo`, we'd likely be parsing `foo` files with the `bar`
+    /// grammar.
+    #[arg(long, default_value = "warn")]
+    grammar_name_mismatch: MismatchPolicy,
 
-    /// A set of CSVs. When using this, the path specified in -o/--output-path
-    /// must be a directory.
-    Csv,
-}
+    /// Only export named nodes, skipping anonymous ones (punctuation,
+    /// keywords, etc.) Edges connect each named node to its nearest named
+    /// ancestor, so the tree stays connected.
+    #[arg(long)]
+    named_only: bool,
 
-static SCHEMA: &str = indoc::indoc! {"
-    {:create nodes {
-        path: String,
-        id: Int,
-        =>
-        kind: String,
-        is_error: Bool,
-        source: String?,
-    }}
-
-    {:create node_locations {
-        path: String,
-        id: Int,
-        =>
-        start_byte: Int,
-        start_row: Int,
-        start_column: Int,
-        end_byte: Int,
-        end_row: Int,
-        end_column: Int,
-    }}
-
-    {:create edges {
-        path: String,
-        parent: Int,
-        child: Int,
-        field: String?,
-    }}
-
-"};
+    /// Don't export nodes of this kind (for example `comment`.) You can
+    /// repeat this. Like --named-only, the children of a dropped node are
+    /// connected to its nearest exported ancestor instead. Those edges don't
+    /// have a field name, since the field (if any) described the dropped
+    /// node's relationship to its children, not the ancestor's.
+    #[arg(long)]
+    exclude_kind: Vec<String>,
 
-struct LanguagesAndPaths {
-    languages: HashSet<String>,
-    paths: Vec<LanguageAndPath>,
-}
+    /// Only export subtrees rooted at nodes of this kind (for example
+    /// `function_item`.) The root of each subtree is recorded in the
+    /// `subtree_roots` relation. When a matching node is nested inside another
+    /// one, it's exported as part of the outer subtree.
+    #[arg(long)]
+    root_node_kind: Option<String>,
 
-struct LanguageAndPath {
-    language: String,
-    path: PathBuf,
-}
+    /// Only export the nodes in part of a file, plus their ancestors, in the
+    /// format `{path}:{start}-{end}`. The range is in lines (1-based and
+    /// inclusive, so `src/main.rs:10-20`) unless it starts with `b`, in which
+    /// case it's in bytes (0-based and exclusive, so `src/main.rs:b100-200`.)
+    /// Other files are exported in full. May be given more than once.
+    #[arg(long)]
+    range: Vec<RangeSelector>,
 
-impl ExporterConfig {
-    #[instrument]
-    pub fn run(&self) -> Result<()> {
-        match self.output {
-            Output::CozoJson => {
-                let db = self.slurp_all().wrap_err("failed to create database")?;
-
-                match db.export_relations(vec!["nodes", "node_locations", "edges"].drain(..)) {
-                    Ok(relations) => {
-                        let json = serde_json::to_string(&relations)
-                            .wrap_err("could not export relations")?;
-                        self.write(&json).wrap_err("could not write output")
-                    }
-                    Err(err) => bail!("{err:#?}"),
-                }
-            }
-            Output::CozoSchema => self.write(SCHEMA).context("could not write schema"),
-            Output::CozoSqlite => match self
-                .slurp_all()
-                .wrap_err("failed to create database")?
-                .backup_db(
-                self.output_path
-                    .as_ref()
-                    .expect(
-                        "if output is sqlite, output path should have been required as an argument",
-                    )
-                    // hmm, it's a little weird that the Cozo API doesn't take a PathBuf...
-                    .display()
-                    .to_string(),
-            ) {
-                Ok(()) => Ok(()),
-                Err(err) => bail!("{err:#?}"),
-            },
-            Output::Csv => {
-                let output_path = self
-                    .output_path
-                    .as_ref()
-                    .ok_or_else(|| eyre!("output_path is required, but should have been validated by clap. Is there a misconfiguration or bug?"))?;
-
-                if !output_path
-                    .metadata()
-                    .wrap_err_with(|| {
-                        format!("could not get metadata for `{}`", output_path.display())
-                    })?
-                    .file_type()
-                    .is_dir()
-                {
-                    bail!(
-                        "For CSV output, we need the output path (`{}`) to be a directory.",
-                        output_path.display()
-                    );
-                }
+    /// Experimental: for each file, walk the subtrees under the root in
+    /// parallel. Parsing still happens one file at a time, but this can help
+    /// when one huge (say, generated) file takes much longer than the rest.
+    #[arg(long)]
+    parallel_subtrees: bool,
 
-                // TODO: we wouldn't necessarily have to use cozo for this!
-                let db = self
-                    .slurp_all()
-                    .wrap_err("could not load source files to database")?;
-
-                let relations =
-                    match db.export_relations(vec!["nodes", "node_locations", "edges"].drain(..)) {
-                        Ok(relations) => relations,
-                        Err(err) => bail!("{err:#?}"),
-                    };
-
-                Self::write_csv(
-                    &output_path.join("nodes.csv"),
-                    relations
-                        .get("nodes")
-                        .expect("nodes should be present in the export above"),
-                )
-                .wrap_err("could not export `nodes.csv`")?;
+    /// Experimental: parse this file first, then use its tree as a starting
+    /// point for parsing the other files in the same language, using
+    /// tree-sitter's incremental parsing. This can speed things up for
+    /// families of near-identical files (like generated variants of the same
+    /// template), but it's likely slower otherwise. The file has to be one of
+    /// the files we're exporting. Use --timing to see if it helps.
+    #[arg(long)]
+    incremental_baseline: Option<PathBuf>,
 
-                Self::write_csv(
-                    &output_path.join("node_locations.csv"),
-                    relations
-                        .get("node_locations")
-                        .expect("node_locations should be present in the export above"),
-                )
-                .wrap_err("could not export `node_locations.csv`")?;
+    /// Number nodes by their position in a walk of each file (0, 1, 2...)
+    /// instead of using tree-sitter's ids, which change from run to run. This
+    /// makes exports reproducible, at a small cost in speed. This is only for
+    /// the default `path-id` keys (content keys are already reproducible),
+    /// so it can't be combined with --relation-key-mode.
+    #[arg(long, conflicts_with = "relation_key_mode")]
+    stable_ids: bool,
 
-                Self::write_csv(
-                    &output_path.join("edges.csv"),
-                    relations
-                        .get("edges")
-                        .expect("edges should be present in the export above"),
-                )
-                .wrap_err("could not export `edges.csv`")
-            }
-        }
-    }
+    /// How should we key node relations? `path-id` keys them by file and
+    /// node id. `content` replaces node ids with a hash of each node's kind
+    /// and source text and drops the path from the keys, so identical
+    /// subtrees (in the same file or across files) collapse into one row.
+    ///
+    /// That includes `node_locations`, which is keyed the same way: when a
+    /// subtree shows up more than once, only one of its locations is kept
+    /// (whichever we happened to import last), so locations aren't a
+    /// reliable way to find every copy in this mode.
+    #[arg(long, default_value = "path-id")]
+    relation_key_mode: KeyMode,
 
-    #[instrument]
-    fn files(&self) -> Result<LanguagesAndPaths> {
-        let mut types_builder = ignore::types::TypesBuilder::new();
-        types_builder.add_defaults();
-        if self.language.is_empty() {
-            types_builder.select("all");
-        } else {
-            for language in &self.language {
-                types_builder.select(language);
-            }
-        }
-        for language in &self.no_language {
-            types_builder.negate(language);
-        }
-        for language in &self.custom_language {
-            types_builder
-                .add_def(language)
-                .wrap_err("could not define custom language")?;
-        }
+    /// Memory-map source files instead of reading them into memory. This
+    /// cuts down on copying for very large files. Only the parts of the file
+    /// we store as `source` need to be valid UTF-8.
+    #[arg(long)]
+    mmap: bool,
 
-        let types = types_builder
-            .build()
-            .wrap_err("could not build filetype matcher")?;
+    /// Decompress gzipped source files (like `data.json.gz`) before parsing
+    /// them. We pick the language from the extension inside the `.gz`, and
+    /// also decompress any other file that turns out to be gzipped. Gzipped
+    /// files are always read into memory, even with --mmap.
+    #[arg(long)]
+    decompress: bool,
 
-        let mut builder = ignore::WalkBuilder::new(match self.file.get(0) {
-            Some(path) => path,
-            None => bail!("expected at least one path to search"),
-        });
-        self.file.iter().skip(1).for_each(|path| {
-            builder.add(path);
-        });
-        builder
-            .types(types.clone())
-            .hidden(!self.no_hidden)
-            .ignore(!self.no_ignore)
-            .git_ignore(!self.no_git_ignore)
-            .git_global(!self.no_git_ignore)
-            .git_exclude(!self.no_git_ignore);
+    /// Put location columns directly in `nodes` instead of in a separate
+    /// `node_locations` relation. This saves a join for most queries, at the
+    /// cost of a wider table.
+    #[arg(long)]
+    wide: bool,
 
-        let mut languages = HashSet::with_capacity(self.language.len().max(1));
-        let mut paths = Vec::with_capacity(self.file.len());
+    /// How should we store locations? `columns` uses six integer columns
+    /// (start and end byte, row, and column.) `packed` uses a single
+    /// `location` string like `3:4-5:0` (`{start_row}:{start_column}-{end_row}:{end_column}`,
+    /// zero-based like the integer columns), which is handy for grep- or
+    /// ctags-style tools.
+    #[arg(long, default_value = "columns")]
+    location_format: LocationFormat,
 
-        for entry_res in builder.build() {
-            let entry = entry_res?;
+    /// Don't export locations at all (no `node_locations` relation.) If you
+    /// only care about the structure of the tree, this saves a good chunk of
+    /// memory and output size.
+    #[arg(long, conflicts_with = "wide")]
+    no_locations: bool,
 
-            if let Some(ft) = entry.file_type() {
-                if !ft.is_file() {
-                    continue;
-                }
-            }
+    /// Add `start_char` and `end_char` columns to the locations: offsets
+    /// from the start of the file in characters rather than bytes, for tools
+    /// that index text that way. Working these out takes an extra pass over
+    /// each file.
+    #[arg(long, conflicts_with = "no_locations")]
+    char_offsets: bool,
 
-            if let ignore::Match::Whitelist(glob) = types.matched(entry.path(), false) {
-                let file_type = match glob.file_type_def() {
-                    Some(ft) => ft,
-                    None => bail!("there's always supposed to be a file type def when the types matched a file path"),
-                };
+    /// Add a `child_start_offset` column to `edges`: how many bytes after the
+    /// start of the parent the child starts.
+    #[arg(long)]
+    edge_offsets: bool,
 
-                languages.insert(file_type.name().to_string());
-                paths.push(LanguageAndPath {
-                    language: file_type.name().to_string(),
-                    path: entry.into_path(),
-                });
-            } else {
-                bail!("got an entry which wasn't a directory and also didn't match any supplied file types. Is this a misconfiguration or a bug?")
-            }
-        }
+    /// Add a `field_id` column to `edges` with tree-sitter's numeric id for
+    /// the field, which is cheaper to join and group on than the name.
+    #[arg(long)]
+    field_ids: bool,
 
-        Ok(LanguagesAndPaths { languages, paths })
-    }
+    /// Add `parent_kind` and `child_kind` columns to `edges`, so that
+    /// filtering edges by what they connect (like children of a
+    /// `function_definition`) doesn't need a join against `nodes`.
+    #[arg(long)]
+    denormalize_edges: bool,
 
-    #[instrument]
-    fn slurp_all(&self) -> Result<cozo::Db<cozo::MemStorage>> {
-        let LanguagesAndPaths {
-            mut languages,
-            paths,
-        } = self.files().wrap_err("could not get files")?;
+    /// Add a `has_field` column to `edges` saying whether the child is in one
+    /// of its parent's fields. Only children the grammar gives a field name
+    /// (like a function's `name` or `body`) have one, so punctuation,
+    /// keywords, and unlabeled children all have a null `field`. Filtering on
+    /// `has_field` says that explicitly.
+    #[arg(long)]
+    has_field: bool,
 
-        let mut loader = Loader::with_capacity(self.include.clone(), languages.len());
-        for language in languages.drain() {
-            loader
-                .preload(language)
-                .wrap_err("could not load language")?;
-        }
+    /// Parse files in order of their paths, instead of whatever order we
+    /// found them in (which depends on the filesystem.) With
+    /// `RAYON_NUM_THREADS=1`, this makes runs (and their logs) the same
+    /// every time. It also makes --sample pick the same files for a given
+    /// --seed on any machine.
+    #[arg(long)]
+    sort_files: bool,
 
-        let mut exporters = paths
-            .par_iter()
-            .map(|LanguageAndPath { language: language_name, path }| {
-                let language = match loader.get(language_name) {
-                    Some(language) => language,
-                    None => bail!("could not get a language definition for `{language_name}`. Was it preloaded?"),
-                };
+    /// Only parse a random sample of this many of the files we find. Handy
+    /// for trying out options on a big repo before doing a full run.
+    #[arg(long)]
+    sample: Option<usize>,
 
-                let mut exporter = FileExporter::new(language, path);
-                exporter
-                    .slurp()
-                    .wrap_err_with(|| format!("could not export from `{}`", path.display()))?;
-                Ok(exporter)
-            })
-            .collect::<Result<Vec<FileExporter<'_>>>>()
-            .wrap_err("failed to parse files")?;
+    /// Seed the random number generator for --sample, so you get the same
+    /// sample every time.
+    #[arg(long, requires = "sample")]
+    seed: Option<u64>,
 
-        let db = self.empty_db().wrap_err("could not set up empty Cozo DB")?;
+    /// Only parse files modified after this time. Accepts a timestamp like
+    /// `2023-01-31T12:00:00Z` or a duration before now like `1h` or `3days`.
+    /// This is a cheap way to re-index only what's changed recently.
+    #[arg(long, visible_alias = "after")]
+    modified_since: Option<ModifiedSince>,
 
-        for exporter in exporters.drain(..) {
-            if let Err(err) = db.import_relations(exporter.into()) {
-                bail!("{err:#?}");
-            };
-        }
+    /// Show each syntax error (and each node the parser had to make up
+    /// because it was missing) as the offending source line with carets
+    /// under the problem, instead of just a `row:column` warning. This is
+    /// ignored when stderr isn't a terminal.
+    #[arg(long)]
+    pretty_errors: bool,
 
-        Ok(db)
-    }
+    /// What to do about syntax errors in the files we parse: `warn` about
+    /// each one, stay `silent`, `fail` the whole run, or `record` them in an
+    /// `errors { path, id => is_missing, start_row, start_column }` relation
+    /// without logging anything.
+    #[arg(long, default_value = "warn")]
+    on_error: ErrorPolicy,
 
-    #[instrument(skip(data))]
-    fn write_csv(path: &Path, data: &NamedRows) -> Result<()> {
-        let nodes_file = std::fs::File::create(path)?;
+    /// Stop once we've seen this many syntax errors across all files,
+    /// instead of scanning the rest. Useful when checking a tree for errors,
+    /// since a misconfigured grammar can turn up thousands of them. We exit
+    /// with status 3 when this happens, so scripts can tell it apart from a
+    /// clean run (0) or some other failure (1).
+    #[arg(long)]
+    max_errors: Option<usize>,
 
-        let mut csv_writer = csv::Writer::from_writer(nodes_file);
-        csv_writer
-            .write_record(&data.headers)
-            .wrap_err("could not write header")?;
+    /// Store at most this many bytes of each node's `source`, marking
+    /// anything we cut short with a trailing `…`. Handy when a few huge
+    /// string literals or embedded blobs would otherwise bloat the export.
+    #[arg(long)]
+    max_source_len: Option<usize>,
 
-        for row in &data.rows {
-            csv_writer.serialize(row).wrap_err("could not write row")?;
-        }
+    /// Add a `source_normalized` column to `nodes` with each node's source
+    /// with runs of whitespace collapsed to a single space (and trimmed.)
+    /// This makes it easy to compare code that only differs in formatting.
+    /// Locations still refer to the original source.
+    #[arg(long)]
+    normalize_source: bool,
 
-        Ok(())
-    }
+    /// Store kinds as `{language}:{kind}` (like `python:comment`) instead of
+    /// just `{kind}`, in `nodes` and everywhere else we store a kind. The
+    /// same kind can mean different things in different grammars, so this
+    /// keeps them apart when querying across languages. The tradeoff is that
+    /// every query that looks for a kind has to include the language, even
+    /// in a single-language export.
+    #[arg(long)]
+    qualify_kinds: bool,
 
-    #[instrument(skip(data))]
-    fn write(&self, data: &str) -> Result<()> {
-        match &self.output_path {
-            None => std::io::stdout()
-                .write(data.as_bytes())
-                .map(|_| ())
-                .wrap_err("could not write to stdout"),
-            Some(path) => std::fs::write(path, data).wrap_err("could not write to output file"),
-        }
-    }
+    /// Add a `kind_counts` relation with how many nodes of each kind are in
+    /// each file. This is much faster to query than counting over `nodes`,
+    /// which is handy for dashboards.
+    #[arg(long)]
+    count_kinds: bool,
 
-    fn empty_db(&self) -> Result<cozo::Db<cozo::MemStorage>> {
-        let db = match cozo::new_cozo_mem() {
-            Ok(db) => db,
-            // Cozo uses miette for error handling. It looks pretty nice, but
-            // it can't be used with color_eyre. Might be worth switching over;
-            // they both seem fine and I don't intend tree-db to ever be used
-            // as a library (if I did, I'd be doing things in this_error or
-            // something similar already.)
-            Err(err) => bail!("{err:#?}"),
-        };
+    /// Add an `error_context { path, error_id => ancestor_id, ancestor_kind }`
+    /// relation linking each error node to its nearest named (and exported)
+    /// ancestor, for a quick view of which functions, classes, etc. have
+    /// syntax errors in them.
+    #[arg(long)]
+    error_context: bool,
 
-        if let Err(err) = db.run_script(SCHEMA, BTreeMap::new()) {
-            bail!("{err:#?}")
-        }
+    /// Add a `tokens { path, id => text, start_byte, end_byte, kind }`
+    /// relation with just the leaves of the tree (named leaves like
+    /// identifiers, and anonymous tokens like keywords and punctuation.)
+    /// Sorted by `start_byte`, this is the file as a flat stream of tokens,
+    /// which is much smaller than `nodes` and all a simple lexer or syntax
+    /// highlighter needs. `text` is null if it isn't valid UTF-8.
+    #[arg(long)]
+    tokens: bool,
 
-        Ok(db)
-    }
+    /// Add a `sibling_edges { path, node => next }` relation linking each
+    /// exported node to the next exported child of the same parent, for
+    /// going through statements (or arguments, etc.) in order.
+    #[arg(long)]
+    sibling_edges: bool,
+
+    /// Add a `source_hash` column to `nodes`: a 64-bit FNV-1a hash of the
+    /// node's source bytes (stored as a signed integer, since that's what
+    /// Cozo has.) Grouping by `kind` and `source_hash` is a cheap way to find
+    /// duplicated code.
+    #[arg(long)]
+    source_hash: bool,
+
+    /// Give every node a `node_uid` that's unique across the whole export
+    /// (a 64-bit hash of its path and id), and add `parent_uid` and
+    /// `child_uid` to `edges`. This is handy for tools that can't join on
+    /// `(path, id)`, like ORMs or foreign keys in SQLite. Combine with
+    /// --stable-ids to get the same uids every time.
+    #[arg(long)]
+    node_uids: bool,
+
+    /// Add a `token` column to `nodes` with the source text of anonymous
+    /// nodes (keywords, operators, punctuation, and so on.) For most grammars
+    /// this is the same as `kind`, but not all, so this is the one to use if
+    /// you're looking for specific tokens. It's null for named nodes.
+    #[arg(long)]
+    token_column: bool,
+
+    /// Add a `source_bytes` column to `nodes` with the same text as `source`,
+    /// but base64-encoded. `source` has to be valid UTF-8, so this is the
+    /// way to keep the contents of grammars over binary or mixed-encoding
+    /// files.
+    #[arg(long)]
+    include_bytes: bool,
+
+    /// Add nullable `first_child`, `last_child`, and `next_sibling` columns
+    /// to `nodes`, so the tree is stored as first-child/next-sibling links.
+    /// Unlike `edges`, this keeps the children's order, and walking a
+    /// subtree doesn't need a scan of every edge.
+    #[arg(long)]
+    linked_tree: bool,
+
+    /// Print how long each phase (finding files, loading grammars, parsing,
+    /// importing into Cozo, and writing output) took to stderr at the end.
+    #[arg(long)]
+    timing: bool,
+
+    /// Write a JSON summary of the run to this path: how many files we
+    /// found, parsed, and skipped (and why), node, edge, and error counts,
+    /// files per language, and how long it all took. Handy for tracking the
+    /// health of an index over time in CI.
+    #[arg(long)]
+    stats_json: Option<PathBuf>,
+
+    /// Which Cozo storage engine should we build the database in? `mem` is
+    /// fastest, but everything has to fit in memory. `sqlite` and `rocksdb`
+    /// keep the database on disk at --engine-path, so they can handle much
+    /// bigger exports (and `rocksdb` scales better than `sqlite`.) `rocksdb`
+    /// is only available if tree-db was built with the `rocksdb` feature.
+    #[arg(long, default_value = "mem")]
+    engine: Engine,
+
+    /// Import each relation into Cozo this many rows at a time, instead of
+    /// all of a file's rows at once. Importing a huge file (like a big
+    /// generated source) in one go can use a lot of memory; smaller chunks
+    /// keep it down at the cost of some speed.
+    #[arg(long)]
+    chunk_size: Option<NonZeroUsize>,
+
+    /// Pick up where an interrupted export left off. This needs an on-disk
+    /// --engine: we'll reuse the database at --engine-path if it exists and
+    /// skip any file it already has with the same contents (according to the
+    /// `hash` in `files`.) Changed files are re-parsed and replace what was
+    /// there before, and files that are gone are removed (unless we're
+    /// only looking at some files, like with --git-changed.) Each file is
+    /// imported as soon as it's parsed, so interrupting an export keeps
+    /// everything it got through.
+    #[arg(long, requires = "engine_path")]
+    resume: bool,
+
+    /// Cache each file's exported rows on disk, keyed by the file's contents,
+    /// the grammar, and the export options, so that exporting files that
+    /// haven't changed since the last run skips parsing them entirely. This
+    /// is handy when iterating on queries over a big repo. Note that we
+    /// won't warn about syntax errors in files we get from the cache.
+    #[arg(long, overrides_with = "no_cache")]
+    parser_cache: bool,
+
+    /// Don't use the parser cache, even if --parser-cache is given earlier
+    /// (for example in an alias.)
+    #[arg(long, overrides_with = "parser_cache")]
+    no_cache: bool,
+
+    /// Empty the parser cache before starting.
+    #[arg(long)]
+    clear_cache: bool,
+
+    /// Where to keep the parser cache. Defaults to `tree-db` in the user's
+    /// cache directory (`$XDG_CACHE_HOME` or `~/.cache`.)
+    #[arg(long, env = "TREE_DB_CACHE_DIR")]
+    cache_dir: Option<PathBuf>,
+
+    /// Where the `sqlite` and `rocksdb` engines should keep their data.
+    #[arg(
+        long,
+        required_if_eq("engine", "sqlite"),
+        required_if_eq("engine", "rocksdb")
+    )]
+    engine_path: Option<PathBuf>,
 }
 
-#[derive(Debug)]
-pub struct FileExporter<'path> {
-    language: Language,
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+pub enum MismatchPolicy {
+    /// Log a warning and carry on
+    Warn,
 
-    path: &'path Path,
-    source: String,
+    /// Stop before parsing anything
+    Fail,
 
-    nodes: Vec<ExportableNode<'path>>,
-    locations: Vec<ExportableNodeLocation<'path>>,
-    edges: Vec<ExportableEdge<'path>>,
+    /// Don't check
+    Ignore,
 }
 
-impl<'path> FileExporter<'path> {
-    fn new(language: Language, path: &'path Path) -> Self {
-        Self {
-            language,
-            path,
-            // TODO: these capacities are really a shot in the dark. It's
-            // probably worth measuring what's typical and then adjusting them.
-            source: String::with_capacity(2 ^ 10),
-            nodes: Vec::with_capacity(2 ^ 10),
-            locations: Vec::with_capacity(2 ^ 10),
-            edges: Vec::with_capacity(2 ^ 10),
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+pub enum ErrorPolicy {
+    /// Log each error (see also --pretty-errors)
+    Warn,
+
+    /// Don't log anything
+    Silent,
+
+    /// Stop at the first file with errors
+    Fail,
+
+    /// Add them to the `errors` relation
+    Record,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+pub enum LocationFormat {
+    /// Separate integer columns
+    Columns,
+
+    /// A single `row:column-row:column` string
+    Packed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+pub enum KeyMode {
+    /// By file and node id
+    PathId,
+
+    /// By a hash of the node's content, for deduplication
+    Content,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+pub enum Engine {
+    /// In memory
+    Mem,
+
+    /// On disk, in SQLite
+    Sqlite,
+
+    /// On disk, in RocksDB
+    Rocksdb,
+}
+
+impl Engine {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Mem => "mem",
+            Self::Sqlite => "sqlite",
+            Self::Rocksdb => "rocksdb",
         }
     }
+}
 
-    #[instrument(skip(self), fields(path = ?self.path))]
-    fn slurp(&mut self) -> Result<()> {
-        self.read_source().wrap_err("could not read source")?;
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct GrammarVersionPolicy {
+    min: Option<usize>,
+    max: Option<usize>,
+}
 
-        let mut parser = Parser::new();
-        parser
-            .set_language(self.language)
-            .wrap_err("could not set parser language")?;
+impl GrammarVersionPolicy {
+    fn allows(&self, version: usize) -> bool {
+        self.min.map_or(true, |min| version >= min) && self.max.map_or(true, |max| version <= max)
+    }
+}
 
-        let tree = match parser.parse(&self.source, None) {
-            Some(tree) => tree,
-            None => bail!("internal error: parser did not return a tree"),
-        };
+impl std::fmt::Display for GrammarVersionPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.min, self.max) {
+            (Some(min), Some(max)) => write!(f, "between {min} and {max}"),
+            (Some(min), None) => write!(f, "at least {min}"),
+            (None, Some(max)) => write!(f, "at most {max}"),
+            (None, None) => write!(f, "anything"),
+        }
+    }
+}
 
-        let mut cursor = tree.walk();
-        let mut todo = vec![tree.root_node()];
+impl std::str::FromStr for GrammarVersionPolicy {
+    type Err = String;
 
-        while let Some(node) = todo.pop() {
-            if node.is_error() {
-                let range = node.range();
-                tracing::warn!(
-                    "`{}` contains an error at {}:{}",
-                    self.path.display(),
-                    range.start_point.row,
-                    range.start_point.column,
-                )
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (min, max) = match s.split_once('-') {
+            Some(bounds) => bounds,
+            None => (s, s),
+        };
+
+        let parse = |bound: &str| -> std::result::Result<Option<usize>, String> {
+            if bound.is_empty() {
+                Ok(None)
+            } else {
+                bound
+                    .parse()
+                    .map(Some)
+                    .map_err(|err| format!("could not parse `{bound}` as a version: {err}"))
             }
+        };
 
-            self.nodes.push(ExportableNode::from(self.path, &node));
-            self.locations
-                .push(ExportableNodeLocation::from(self.path, &node));
+        Ok(Self {
+            min: parse(min)?,
+            max: parse(max)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct LanguageAlias {
+    label: String,
+    grammar: String,
+}
 
-            for (i, child) in node.children(&mut cursor).enumerate() {
-                todo.push(child);
+impl std::str::FromStr for LanguageAlias {
+    type Err = String;
 
-                self.edges.push(ExportableEdge {
-                    path: self.path,
-                    parent: node.id(),
-                    child: node.id(),
-                    field: node.field_name_for_child(i as u32),
-                })
-            }
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((label, grammar)) if !label.is_empty() && !grammar.is_empty() => Ok(Self {
+                label: label.to_string(),
+                grammar: grammar.to_string(),
+            }),
+            _ => Err(format!("expected `{{label}}={{grammar}}`, but got `{s}`")),
         }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct GrammarPath {
+    name: String,
+    path: PathBuf,
+}
+
+impl std::str::FromStr for GrammarPath {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((name, path)) if !name.is_empty() && !path.is_empty() => Ok(Self {
+                name: name.to_string(),
+                path: PathBuf::from(path),
+            }),
+            _ => Err(format!("expected `{{name}}={{path}}`, but got `{s}`")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct RangeSelector {
+    path: PathBuf,
+    span: Span,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+enum Span {
+    Lines(usize, usize),
+    Bytes(usize, usize),
+}
+
+impl RangeSelector {
+    /// Whether this selector is talking about the file at `path`. Both are
+    /// relative to --root, if it's set.
+    fn matches(&self, config: &SourceConfig, path: &Path) -> bool {
+        let (ours, theirs) = (config.resolve(&self.path), config.resolve(path));
+        match (ours.canonicalize(), theirs.canonicalize()) {
+            (Ok(ours), Ok(theirs)) => ours == theirs,
+            _ => ours == theirs,
+        }
+    }
+
+    /// The selected bytes in `source`.
+    fn bytes(&self, source: &[u8]) -> Result<std::ops::Range<usize>> {
+        match self.span {
+            Span::Bytes(start, end) => {
+                if end > source.len() {
+                    bail!(
+                        "byte range {start}-{end} is past the end of `{}`, which is {} bytes long",
+                        self.path.display(),
+                        source.len()
+                    );
+                }
+
+                Ok(start..end)
+            }
+            Span::Lines(start, end) => {
+                let line_starts: Vec<usize> = std::iter::once(0)
+                    .chain(
+                        source
+                            .iter()
+                            .enumerate()
+                            .filter(|(_, byte)| **byte == b'\n')
+                            .map(|(i, _)| i + 1),
+                    )
+                    .collect();
+
+                if end > line_starts.len() {
+                    bail!(
+                        "line range {start}-{end} is past the end of `{}`, which has {} lines",
+                        self.path.display(),
+                        line_starts.len()
+                    );
+                }
+
+                Ok(line_starts[start - 1]..line_starts.get(end).copied().unwrap_or(source.len()))
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for RangeSelector {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (path, span) = s
+            .rsplit_once(':')
+            .ok_or_else(|| format!("expected `{{path}}:{{start}}-{{end}}`, but got `{s}`"))?;
+
+        let (is_bytes, span) = match span.strip_prefix('b') {
+            Some(rest) => (true, rest),
+            None => (false, span),
+        };
+
+        let (start, end) = span
+            .split_once('-')
+            .ok_or_else(|| format!("expected a range like `10-20`, but got `{span}`"))?;
+        let start: usize = start
+            .parse()
+            .map_err(|err| format!("could not parse `{start}` as a number: {err}"))?;
+        let end: usize = end
+            .parse()
+            .map_err(|err| format!("could not parse `{end}` as a number: {err}"))?;
+
+        if start > end {
+            return Err(format!("the range {start}-{end} ends before it starts"));
+        }
+
+        let span = if is_bytes {
+            Span::Bytes(start, end)
+        } else if start == 0 {
+            return Err("line numbers start at 1".to_string());
+        } else {
+            Span::Lines(start, end)
+        };
+
+        Ok(Self {
+            path: PathBuf::from(path),
+            span,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub struct ModifiedSince(std::time::SystemTime);
+
+impl std::str::FromStr for ModifiedSince {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if let Ok(time) = humantime::parse_rfc3339_weak(s) {
+            return Ok(Self(time));
+        }
+
+        let duration = humantime::parse_duration(s)
+            .map_err(|err| format!("could not parse `{s}` as a timestamp or a duration: {err}"))?;
+
+        std::time::SystemTime::now()
+            .checked_sub(duration)
+            .map(Self)
+            .ok_or_else(|| format!("`{s}` is too far in the past"))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, clap::ValueEnum)]
+pub enum Output {
+    /// Cozo relations, as JSON
+    CozoJson,
+
+    /// The Cozo schema that we're assuming as a query you can run to start
+    /// your own Cozo database.
+    CozoSchema,
+
+    /// A SQLite database, as a file. With --sqlite-per-file, we write one
+    /// database per input file into the -o/--output-path directory instead,
+    /// at the file's path plus `.sqlite` (so `src/main.rs` becomes
+    /// `{output-path}/src/main.rs.sqlite`.)
+    CozoSqlite,
+
+    /// Each file's syntax tree as nested JSON (each node has a `children`
+    /// array), one file per line. This is often easier to work with than
+    /// flat relations from languages like JavaScript or Python. Each line is
+    /// written as soon as its file is parsed, so the files come out in the
+    /// order they finish rather than the order we found them.
+    TreeJson,
+
+    /// A SQL script for Postgres that creates a table for each relation and
+    /// loads the data with `COPY ... FROM stdin`. Run it with `psql -f`.
+    PostgresCopy,
+
+    /// A DuckDB database, as a file, with a table for each relation. This is
+    /// only available if tree-db was built with the `duckdb` feature.
+    #[value(name = "duckdb")]
+    DuckDb,
+
+    /// Cypher statements that create the tree in Neo4j (or Memgraph), for
+    /// piping into `cypher-shell`. Nodes get every column from `nodes` as
+    /// properties, and are created in batches.
+    Cypher,
+
+    /// A Mermaid diagram of the syntax tree, for pasting into Markdown.
+    /// This is only practical for small files; we stop drawing after 500
+    /// nodes.
+    Mermaid,
+
+    /// A set of CSVs. When using this, the path specified in -o/--output-path
+    /// must be a directory (we'll create it if it doesn't exist yet.)
+    Csv,
+
+    /// `nodes.csv` and `edges.csv` in the format `neo4j-admin database
+    /// import` expects, for loading into Neo4j or Memgraph. Like `csv`,
+    /// -o/--output-path must be a directory.
+    Neo4jCsv,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SchemaTarget {
+    /// A Cozo script (the same as the `cozo-schema` output)
+    Cozo,
+
+    /// SQLite `CREATE TABLE` statements
+    Sqlite,
+
+    /// A JSON map of pandas dtypes for each relation
+    Pandas,
+}
+
+impl Output {
+    fn needs_output_path(&self) -> bool {
+        matches!(
+            self,
+            Self::CozoSqlite | Self::DuckDb | Self::Csv | Self::Neo4jCsv
+        )
+    }
+
+    /// How to write this output. We make these before parsing anything, so
+    /// that problems with the options show up right away.
+    fn writer(&self, cfg: &ExporterConfig) -> Result<Box<dyn OutputWriter>> {
+        Ok(match self {
+            Self::CozoJson => Box::new(CozoJsonWriter),
+            Self::CozoSchema => Box::new(CozoSchemaWriter),
+            Self::CozoSqlite => Box::new(CozoSqliteWriter::new(cfg)?),
+            Self::TreeJson => Box::new(TreeJsonWriter::new(cfg)?),
+            Self::PostgresCopy => Box::new(PostgresCopyWriter),
+            Self::DuckDb => Box::new(DuckDbWriter::new()?),
+            Self::Cypher => Box::new(CypherWriter),
+            Self::Mermaid => Box::new(MermaidWriter),
+            Self::Csv => Box::new(CsvWriter),
+            Self::Neo4jCsv => Box::new(Neo4jCsvWriter),
+        })
+    }
+}
+
+/// How to write an output format. Most are written from the relations in
+/// the finished database, so they only need `write_relations`. The rest
+/// override the hooks for the part of the export they need instead. To add
+/// an output, implement this and return it from `Output::writer`.
+pub trait OutputWriter: Sync {
+    /// Whether we need to parse any files for this output.
+    fn needs_files(&self) -> bool {
+        true
+    }
+
+    /// Whether we need to import the parsed files into a database for this
+    /// output.
+    fn needs_db(&self) -> bool {
+        true
+    }
+
+    /// Whether to send each file's nested tree to `write_tree` while we
+    /// parse. We don't build the trees otherwise.
+    fn wants_trees(&self) -> bool {
+        false
+    }
+
+    /// Write one file's nested tree as soon as it's parsed. This is called
+    /// from several threads at once.
+    fn write_tree(&self, path: &Path, _tree: &Value) -> Result<()> {
+        bail!(
+            "internal error: got a tree for `{}`, but this output doesn't write trees",
+            path.display()
+        )
+    }
+
+    /// Write from the parsed files, before we import them into a database.
+    fn write_parsed(&self, _parsed: &Parsed, _cfg: &ExporterConfig) -> Result<()> {
+        Ok(())
+    }
+
+    /// Write once everything's done. `db` is only there if `needs_db` said
+    /// so. By default, we export `relation_names` from it and hand them to
+    /// `write_relations`.
+    fn finish(&self, db: Option<&cozo::DbInstance>, cfg: &ExporterConfig) -> Result<()> {
+        let relations = db
+            .ok_or_else(|| eyre!("internal error: the database should have been built"))?
+            .export_relations(self.relation_names(cfg).into_iter())
+            .into_report()?;
+
+        self.write_relations(&relations, cfg)
+    }
+
+    /// Which relations to export from the database for this output.
+    /// Defaults to all of them.
+    fn relation_names(&self, cfg: &ExporterConfig) -> Vec<&'static str> {
+        cfg.source.relation_names()
+    }
+
+    fn write_relations(
+        &self,
+        _relations: &BTreeMap<String, NamedRows>,
+        _cfg: &ExporterConfig,
+    ) -> Result<()> {
+        bail!("internal error: this output isn't written from relations")
+    }
+}
+
+struct CozoJsonWriter;
+
+impl OutputWriter for CozoJsonWriter {
+    fn write_relations(
+        &self,
+        relations: &BTreeMap<String, NamedRows>,
+        cfg: &ExporterConfig,
+    ) -> Result<()> {
+        let mut writer = cfg.writer().wrap_err("could not open output")?;
+        serde_json::to_writer(&mut writer, relations).wrap_err("could not export relations")?;
+        writer.flush().wrap_err("could not write output")
+    }
+}
+
+/// The schema only depends on the options, so we don't parse anything for
+/// it.
+struct CozoSchemaWriter;
+
+impl OutputWriter for CozoSchemaWriter {
+    fn needs_files(&self) -> bool {
+        false
+    }
+
+    fn needs_db(&self) -> bool {
+        false
+    }
+
+    fn finish(&self, _: Option<&cozo::DbInstance>, cfg: &ExporterConfig) -> Result<()> {
+        cfg.write(&schema::to_cozo(&cfg.source.schema()))
+            .context("could not write schema")
+    }
+}
+
+/// Backs up the whole database to -o/--output-path, or with
+/// --sqlite-per-file, writes one database per parsed file without building
+/// the whole thing.
+struct CozoSqliteWriter {
+    per_file: bool,
+}
+
+impl CozoSqliteWriter {
+    fn new(cfg: &ExporterConfig) -> Result<Self> {
+        if !cfg.sqlite_per_file {
+            if let Some(path) = cfg.output_path.as_ref().filter(|path| path.is_dir()) {
+                bail!(
+                    "`{}` is a directory, but cozo-sqlite writes a single database. Use --sqlite-per-file to write one database per input file into it.",
+                    path.display()
+                );
+            }
+        }
+
+        Ok(Self {
+            per_file: cfg.sqlite_per_file,
+        })
+    }
+}
+
+impl OutputWriter for CozoSqliteWriter {
+    fn needs_db(&self) -> bool {
+        !self.per_file
+    }
+
+    fn write_parsed(&self, parsed: &Parsed, cfg: &ExporterConfig) -> Result<()> {
+        if !self.per_file {
+            return Ok(());
+        }
+
+        let output_dir = cfg.output_dir("per-file cozo-sqlite")?;
+        cfg.write_sqlite_per_file(parsed, output_dir)
+            .wrap_err("could not write per-file SQLite databases")
+    }
+
+    fn finish(&self, db: Option<&cozo::DbInstance>, cfg: &ExporterConfig) -> Result<()> {
+        if self.per_file {
+            return Ok(());
+        }
+
+        cfg.backup_db(
+            db.ok_or_else(|| eyre!("internal error: the database should have been built"))?,
+            cfg.output_path.as_ref().expect(
+                "if output is sqlite, output path should have been required as an argument",
+            ),
+        )
+    }
+}
+
+/// Trees go out as each file is parsed, instead of all being kept around
+/// until the end.
+struct TreeJsonWriter {
+    writer: Mutex<BufWriter<Box<dyn Write + Send>>>,
+}
+
+impl TreeJsonWriter {
+    fn new(cfg: &ExporterConfig) -> Result<Self> {
+        Ok(Self {
+            writer: Mutex::new(cfg.writer().wrap_err("could not open output")?),
+        })
+    }
+}
+
+impl OutputWriter for TreeJsonWriter {
+    fn needs_db(&self) -> bool {
+        false
+    }
+
+    fn wants_trees(&self) -> bool {
+        true
+    }
+
+    fn write_tree(&self, path: &Path, tree: &Value) -> Result<()> {
+        let mut writer = self
+            .writer
+            .lock()
+            .expect("a thread panicked while writing a tree");
+        serde_json::to_writer(&mut *writer, tree)
+            .wrap_err_with(|| format!("could not write tree for `{}`", path.display()))?;
+        writer.write_all(b"\n").wrap_err("could not write output")
+    }
+
+    fn write_parsed(&self, _: &Parsed, _: &ExporterConfig) -> Result<()> {
+        self.writer
+            .lock()
+            .expect("a thread panicked while writing a tree")
+            .flush()
+            .wrap_err("could not write output")
+    }
+}
+
+/// Everything we parsed, ready to import into a database.
+pub struct Parsed {
+    languages: NamedRows,
+    files: Vec<ParsedFile>,
+
+    /// With --resume, the database we imported each file into as soon as we
+    /// parsed it (so an interrupted export keeps the files it got through.)
+    db: Option<cozo::DbInstance>,
+}
+
+impl Parsed {
+    fn languages(&self) -> BTreeMap<String, NamedRows> {
+        BTreeMap::from([("languages".into(), self.languages.clone())])
+    }
+}
+
+struct ParsedFile {
+    path: PathBuf,
+
+    /// Whether this file was already in the database we're resuming, and so
+    /// replaces what was there.
+    replaces: bool,
+
+    relations: BTreeMap<String, NamedRows>,
+
+    /// How many error and missing nodes are in the file's tree, including
+    /// any we didn't export.
+    error_nodes: usize,
+}
+
+/// We stopped early because of --max-errors. `main` looks for this to pick
+/// the exit status.
+#[derive(Debug)]
+pub struct MaxErrorsReached {
+    pub max: usize,
+}
+
+impl std::fmt::Display for MaxErrorsReached {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "hit the limit of {} syntax errors (--max-errors)",
+            self.max
+        )
+    }
+}
+
+impl std::error::Error for MaxErrorsReached {}
+
+/// `SourceConfig` options that can't change the rows we export for a file,
+/// so changing them shouldn't miss the parser cache: they pick which files
+/// we look at (the path, language, and contents are in the key already),
+/// decide whether to stop or skip rather than what to export, or are about
+/// where things go and how we report on the run. Every other option is part
+/// of the cache key, so a new option can only ever cause extra misses, not
+/// stale hits.
+static CACHE_IGNORES: &[&str] = &[
+    "language",
+    "no_language",
+    "custom_language",
+    "types_file",
+    "language_overrides",
+    "root",
+    "file",
+    "no_normalize_paths",
+    "no_hidden",
+    "git_changed",
+    "no_shebang",
+    "no_ignore",
+    "no_git_ignore",
+    "grammar_version_check",
+    "skip_broken_grammars",
+    "grammar_name_mismatch",
+    "mmap",
+    "decompress",
+    "sort_files",
+    "sample",
+    "seed",
+    "modified_since",
+    "pretty_errors",
+    "max_errors",
+    "timing",
+    "stats_json",
+    "engine",
+    "chunk_size",
+    "resume",
+    "parser_cache",
+    "no_cache",
+    "clear_cache",
+    "cache_dir",
+    "engine_path",
+];
+
+/// Everything `SourceConfig::parse_file` needs from `parse` besides the
+/// file itself.
+struct ParseContext<'parse> {
+    run: &'parse RunContext,
+    loader: &'parse Loader,
+    broken: &'parse [(String, color_eyre::Report)],
+    previous: &'parse HashMap<String, i64>,
+    baseline: Option<&'parse Baseline>,
+    cache: Option<&'parse ParserCache>,
+    fingerprint: &'parse [u8],
+    trees: Option<&'parse TreeSink<'parse>>,
+
+    /// With --resume, where to import each file once it's parsed. We import
+    /// one file at a time so that Cozo doesn't see conflicting writes.
+    db: Option<&'parse Mutex<cozo::DbInstance>>,
+}
+
+/// What we keep track of over a single run (one export, or one query),
+/// as opposed to the options in `SourceConfig`, which don't change. With
+/// --interval, each export gets a fresh one.
+#[derive(Debug, Default)]
+pub struct RunContext {
+    timings: Timings,
+    stats: Stats,
+
+    /// Syntax errors across every file so far, for --max-errors.
+    errors_seen: AtomicUsize,
+}
+
+impl RunContext {
+    pub fn timings(&self) -> &Timings {
+        &self.timings
+    }
+}
+
+/// Where `SourceConfig::parse` sends each file's tree as nested JSON, as
+/// soon as the file is parsed. This gets called from many threads at once.
+pub type TreeSink<'sink> = dyn Fn(&Path, &Value) -> Result<()> + Sync + 'sink;
+
+struct LanguagesAndPaths {
+    languages: HashSet<String>,
+    paths: Vec<LanguageAndPath>,
+}
+
+struct LanguageAndPath {
+    language: String,
+    path: PathBuf,
+}
+
+impl ExporterConfig {
+    /// See `SourceConfig::usage_error`.
+    pub fn usage_error(&self) -> Option<&'static str> {
+        self.source.usage_error()
+    }
+
+    #[instrument]
+    pub fn run(&self) -> Result<()> {
+        match self.interval {
+            Some(interval) => self.run_every(*interval),
+            None => self.export(),
+        }
+    }
+
+    /// Export over and over for --interval until we get a SIGINT. We never
+    /// stop partway through an export (unless interrupted twice), so the
+    /// output is never left half-written.
+    fn run_every(&self, interval: std::time::Duration) -> Result<()> {
+        if self.source.engine != Engine::Mem && !self.source.resume {
+            bail!(
+                "--interval with the {} engine needs --resume, or the second export would find the database from the first one already there",
+                self.source.engine.name()
+            );
+        }
+
+        let interrupted = std::sync::Arc::new(AtomicBool::new(false));
+        let (stop, stopped) = std::sync::mpsc::channel();
+        let handler_interrupted = interrupted.clone();
+        ctrlc::set_handler(move || {
+            // a second interrupt means they don't want to wait for the
+            // current export to finish.
+            if handler_interrupted.swap(true, Ordering::SeqCst) {
+                std::process::exit(130);
+            }
+            tracing::info!("interrupted, stopping after this export");
+            // the receiver only goes away once we're done anyway
+            let _ = stop.send(());
+        })
+        .wrap_err("could not set up a SIGINT handler")?;
+
+        let mut exports = 0_usize;
+        loop {
+            let start = std::time::Instant::now();
+
+            match self.export() {
+                Ok(()) => exports += 1,
+                // if the first export fails, it's probably our options
+                // rather than something that'll clear up next time.
+                Err(err) if exports == 0 => return Err(err),
+                Err(err) => tracing::error!(?err, "export failed, will try again next time"),
+            }
+
+            if interrupted.load(Ordering::SeqCst) {
+                break;
+            }
+
+            let wait = interval.saturating_sub(start.elapsed());
+            tracing::info!(?wait, "waiting for the next export");
+            match stopped.recv_timeout(wait) {
+                Err(std::sync::mpsc::RecvTimeoutError::Timeout) => continue,
+                Ok(()) | Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => break,
+            }
+        }
+
+        tracing::info!(exports, "stopped");
+        Ok(())
+    }
+
+    fn export(&self) -> Result<()> {
+        let run = RunContext::default();
+
+        if let Some(target) = self.print_schema_for {
+            let relations = self.source.schema();
+            let schema = match target {
+                SchemaTarget::Cozo => schema::to_cozo(&relations),
+                SchemaTarget::Sqlite => schema::to_sqlite(&relations),
+                SchemaTarget::Pandas => schema::to_pandas(&relations),
+            };
+
+            return writeln!(std::io::stdout(), "{}", schema.trim_end())
+                .wrap_err("could not write schema");
+        }
+
+        // this would be `required_unless_present`, but clap doesn't count
+        // defaults from `.tree-db.toml` towards that.
+        if self.output.is_empty() {
+            bail!(
+                "tell us at least one output format (like `cozo-json`), or use --print-schema-for"
+            );
+        }
+
+        let path_outputs: Vec<&Output> = self
+            .output
+            .iter()
+            .filter(|output| output.needs_output_path())
+            .collect();
+        if path_outputs.len() > 1 {
+            bail!(
+                "only one output can write to -o/--output-path, but {path_outputs:?} all need to"
+            );
+        }
+
+        // with more than one output, stream outputs all go to stdout (see
+        // `stream_path`), one after another with nothing in between.
+        let stdout_outputs: Vec<&Output> = self
+            .output
+            .iter()
+            .filter(|output| !output.needs_output_path())
+            .collect();
+        if self.output.len() > 1 && stdout_outputs.len() > 1 {
+            bail!(
+                "only one output can write to stdout, but {stdout_outputs:?} all would. Run tree-db once for each of them instead."
+            );
+        }
+
+        if self.sqlite_per_file && !self.output.contains(&Output::CozoSqlite) {
+            bail!("--sqlite-per-file only makes sense with the cozo-sqlite output");
+        }
+
+        let writers = self
+            .output
+            .iter()
+            .map(|output| output.writer(self))
+            .collect::<Result<Vec<_>>>()?;
+
+        let tree_writers: Vec<&dyn OutputWriter> = writers
+            .iter()
+            .filter(|writer| writer.wants_trees())
+            .map(|writer| writer.as_ref())
+            .collect();
+        let write_tree = |path: &Path, tree: &Value| -> Result<()> {
+            for writer in &tree_writers {
+                writer.write_tree(path, tree)?;
+            }
+            Ok(())
+        };
+
+        let parsed = if writers.iter().any(|writer| writer.needs_files()) {
+            Some(
+                self.source
+                    .parse(
+                        &run,
+                        (!tree_writers.is_empty()).then_some(&write_tree as &TreeSink),
+                    )
+                    .wrap_err("could not parse files")?,
+            )
+        } else {
+            None
+        };
+
+        if let Some(parsed) = &parsed {
+            for (output, writer) in self.output.iter().zip(&writers) {
+                run.timings
+                    .time("writing", || writer.write_parsed(parsed, self))
+                    .wrap_err_with(|| format!("could not write {output:?} output"))?;
+            }
+        }
+
+        let db = match parsed {
+            Some(parsed) if writers.iter().any(|writer| writer.needs_db()) => Some(
+                self.source
+                    .import(&run, parsed)
+                    .wrap_err("failed to create database")?,
+            ),
+            _ => None,
+        };
+
+        for (output, writer) in self.output.iter().zip(&writers) {
+            run.timings
+                .time("writing", || writer.finish(db.as_ref(), self))
+                .wrap_err_with(|| format!("could not write {output:?} output"))?;
+        }
+
+        self.source.report_timings(&run);
+        self.source.report_stats(&run)?;
+
+        Ok(())
+    }
+
+    /// -o/--output-path, for outputs that write several files into it. We
+    /// create it if it's not there yet.
+    pub fn output_dir(&self, what: &str) -> Result<&Path> {
+        let output_path = self
+            .output_path
+            .as_ref()
+            .ok_or_else(|| eyre!("output_path is required, but should have been validated by clap. Is there a misconfiguration or bug?"))?;
+
+        if !output_path.exists() {
+            std::fs::create_dir_all(output_path)
+                .wrap_err_with(|| format!("could not create `{}`", output_path.display()))?;
+        } else if !output_path
+            .metadata()
+            .wrap_err_with(|| format!("could not get metadata for `{}`", output_path.display()))?
+            .file_type()
+            .is_dir()
+        {
+            bail!(
+                "For {what} output, we need the output path (`{}`) to be a directory.",
+                output_path.display()
+            );
+        }
+
+        Ok(output_path)
+    }
+
+    #[instrument(skip(self, parsed))]
+    fn write_sqlite_per_file(&self, parsed: &Parsed, output_dir: &Path) -> Result<()> {
+        for file in &parsed.files {
+            // mirror the source tree under the output directory, dropping any
+            // leading `/` or `..` so we can't write outside of it.
+            let relative: PathBuf = file
+                .path
+                .components()
+                .filter(|component| matches!(component, std::path::Component::Normal(_)))
+                .collect();
+            let mut out = output_dir.join(relative).into_os_string();
+            out.push(".sqlite");
+            let out = PathBuf::from(out);
+
+            if let Some(parent) = out.parent() {
+                std::fs::create_dir_all(parent)
+                    .wrap_err_with(|| format!("could not create `{}`", parent.display()))?;
+            }
+
+            let db = self
+                .source
+                .empty_db_in(Engine::Mem, None)
+                .wrap_err("could not set up empty Cozo DB")?;
+
+            db.import_relations(parsed.languages()).into_report()?;
+            self.source.import_relations(&db, file.relations.clone())?;
+
+            tracing::debug!(out = ?out, "writing per-file database");
+            self.backup_db(&db, &out)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write `db` to the SQLite database at `out`. Cozo won't back up over a
+    /// database that already has data, so with --interval (where `out` is
+    /// there from last time) we write next to it and then move it into
+    /// place. That way, anything reading `out` never sees it half-written.
+    fn backup_db(&self, db: &cozo::DbInstance, out: &Path) -> Result<()> {
+        // hmm, it's a little weird that the Cozo API doesn't take a PathBuf...
+        if self.interval.is_none() {
+            return db.backup_db(out.display().to_string()).into_report();
+        }
+
+        let mut partial = out.as_os_str().to_owned();
+        partial.push(".partial");
+        let partial = PathBuf::from(partial);
+
+        // left over from an export that was interrupted
+        if partial.exists() {
+            std::fs::remove_file(&partial)
+                .wrap_err_with(|| format!("could not remove `{}`", partial.display()))?;
+        }
+
+        db.backup_db(partial.display().to_string()).into_report()?;
+        std::fs::rename(&partial, out).wrap_err_with(|| {
+            format!(
+                "could not move `{}` to `{}`",
+                partial.display(),
+                out.display()
+            )
+        })
+    }
+
+    /// Stream outputs (JSON and the schema) only write to -o/--output-path
+    /// when they're the only thing we're writing.
+    fn stream_path(&self) -> Option<&PathBuf> {
+        if self.output.len() == 1 {
+            self.output_path.as_ref()
+        } else {
+            None
+        }
+    }
+
+    #[instrument(skip(data))]
+    pub fn write(&self, data: &str) -> Result<()> {
+        let mut writer = self.writer().wrap_err("could not open output")?;
+        writer
+            .write_all(data.as_bytes())
+            .and_then(|_| writer.flush())
+            .wrap_err("could not write output")
+    }
+
+    /// Get a buffered writer for stream outputs, so we don't have to hold
+    /// big exports in memory all at once before writing them.
+    pub fn writer(&self) -> Result<BufWriter<Box<dyn Write + Send>>> {
+        let inner: Box<dyn Write + Send> =
+            match self.stream_path() {
+                None => Box::new(std::io::stdout()),
+                Some(path) => Box::new(std::fs::File::create(path).wrap_err_with(|| {
+                    format!("could not create output file `{}`", path.display())
+                })?),
+            };
+
+        Ok(BufWriter::new(inner))
+    }
+}
+
+impl SourceConfig {
+    /// Combinations of arguments that clap can't rule out by itself, since
+    /// it doesn't count defaults (like `--engine mem`) towards conflicts.
+    /// `main` reports these the same way clap would.
+    pub fn usage_error(&self) -> Option<&'static str> {
+        if self.resume && self.engine == Engine::Mem {
+            return Some(
+                "--resume needs an on-disk --engine (`sqlite` or `rocksdb`), since the `mem` engine starts out empty every time",
+            );
+        }
+
+        None
+    }
+
+    #[instrument(skip(run))]
+    fn files(&self, run: &RunContext) -> Result<LanguagesAndPaths> {
+        let mut types_builder = ignore::types::TypesBuilder::new();
+        types_builder.add_defaults();
+        if self.language.is_empty() {
+            types_builder.select("all");
+        } else {
+            for language in &self.language {
+                types_builder.select(language);
+            }
+        }
+        for language in &self.no_language {
+            types_builder.negate(language);
+        }
+        for language in &self.custom_language {
+            types_builder
+                .add_def(language)
+                .wrap_err("could not define custom language")?;
+        }
+        if let Some(types_file) = &self.types_file {
+            let contents = std::fs::read_to_string(types_file)
+                .wrap_err_with(|| format!("could not read `{}`", types_file.display()))?;
+
+            for (i, line) in contents.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+
+                types_builder.add_def(line).wrap_err_with(|| {
+                    format!(
+                        "could not define custom language on line {} of `{}`",
+                        i + 1,
+                        types_file.display()
+                    )
+                })?;
+            }
+        }
+
+        let types = types_builder
+            .build()
+            .wrap_err("could not build filetype matcher")?;
+
+        let overrides = match &self.language_overrides {
+            Some(path) => Some(
+                LanguageOverrides::from_file(path).wrap_err("could not load language overrides")?,
+            ),
+            None => None,
+        };
+
+        let file_args = FileArgs::new(
+            &self
+                .file
+                .iter()
+                .map(|file| self.resolve(file))
+                .collect::<Vec<PathBuf>>(),
+        )
+        .wrap_err("could not parse file arguments")?;
+
+        let root = match &self.root {
+            Some(root) => Some(
+                root.canonicalize()
+                    .wrap_err_with(|| format!("could not canonicalize `{}`", root.display()))?,
+            ),
+            None => None,
+        };
+
+        // when every argument is a plain file, there's nothing to walk, so
+        // we can skip setting up the walker (and reading ignore files.) The
+        // walker doesn't apply ignore rules to the paths it starts from
+        // anyway, so the results are the same.
+        let direct = file_args.all_files();
+        let entries: Box<dyn Iterator<Item = Result<PathBuf>>> = if direct {
+            Box::new(file_args.roots.clone().into_iter().map(Ok))
+        } else {
+            let mut builder = ignore::WalkBuilder::new(match file_args.roots.get(0) {
+                Some(path) => path,
+                None => bail!("expected at least one path to search"),
+            });
+            file_args.roots.iter().skip(1).for_each(|path| {
+                builder.add(path);
+            });
+            // `WalkBuilder::types` would drop everything that doesn't match
+            // a file type, but we still want extensionless files (for their
+            // shebangs), and with --decompress, `.gz` files won't match until
+            // we look past the extension. We pick the language for the files
+            // that make it through below.
+            let walk_types = types.clone();
+            let (shebangs, decompress) = (!self.no_shebang, self.decompress);
+            builder.filter_entry(move |entry| {
+                if !entry.file_type().map_or(false, |ft| ft.is_file()) {
+                    return true;
+                }
+
+                let path = entry.path();
+                let typed = match path.extension() {
+                    Some(extension) if decompress && extension == "gz" => path.with_extension(""),
+                    _ => path.to_path_buf(),
+                };
+
+                match walk_types.matched(&typed, false) {
+                    ignore::Match::Whitelist(_) => true,
+                    ignore::Match::Ignore(_) => false,
+                    ignore::Match::None => shebangs && typed.extension().is_none(),
+                }
+            });
+            builder
+                .hidden(!self.no_hidden)
+                .ignore(!self.no_ignore)
+                .git_ignore(!self.no_git_ignore)
+                .git_global(!self.no_git_ignore)
+                .git_exclude(!self.no_git_ignore);
+
+            Box::new(builder.build().filter_map(|entry_res| match entry_res {
+                Ok(entry) if entry.file_type().map_or(false, |ft| !ft.is_file()) => None,
+                Ok(entry) => Some(Ok(entry.into_path())),
+                Err(err) => Some(Err(err.into())),
+            }))
+        };
+
+        let mut languages = HashSet::with_capacity(self.language.len().max(1));
+        let mut paths = Vec::with_capacity(self.file.len());
+
+        // overlapping arguments (like a directory and a file inside it) make
+        // the walker yield the same file more than once, which would break
+        // the `(path, id)` keys when we import.
+        let mut seen = HashSet::with_capacity(self.file.len());
+
+        let changed = if self.git_changed {
+            // ask about the repo we're exporting from, which might not be
+            // the one we're running in.
+            let changed = git::changed_files(root.as_deref().unwrap_or_else(|| Path::new(".")))
+                .wrap_err("could not get changed files from git")?;
+            if changed.is_none() {
+                tracing::warn!("--git-changed was set, but we're not in a git repo. Parsing all files instead.");
+            }
+            changed
+        } else {
+            None
+        };
+
+        for entry in entries {
+            let entry = entry?;
+
+            if !file_args.includes(&entry) {
+                continue;
+            }
+
+            if let Some(ModifiedSince(since)) = self.modified_since {
+                let modified = std::fs::metadata(&entry)
+                    .wrap_err_with(|| format!("could not get metadata for `{}`", entry.display()))?
+                    .modified()
+                    .wrap_err_with(|| format!("could not get mtime for `{}`", entry.display()))?;
+                if modified <= since {
+                    run.stats.skip("not_modified_since");
+                    continue;
+                }
+            }
+
+            // `foo.json.gz` is JSON, as far as picking a language goes.
+            let typed = match entry.extension() {
+                Some(extension) if self.decompress && extension == "gz" => entry.with_extension(""),
+                _ => entry.clone(),
+            };
+
+            let detected = match types.matched(&typed, false) {
+                ignore::Match::Whitelist(glob) => match glob.file_type_def() {
+                    Some(ft) => ft.name(),
+                    None => bail!("there's always supposed to be a file type def when the types matched a file path"),
+                },
+                ignore::Match::Ignore(_) => continue,
+                // the walker filters by type for us, but we don't have it
+                // when we're looking at files directly.
+                ignore::Match::None if self.no_shebang && (direct || self.decompress) => continue,
+                ignore::Match::None if self.no_shebang => bail!("got an entry which wasn't a directory and also didn't match any supplied file types. Is this a misconfiguration or a bug?"),
+                // only scripts without an extension, so we don't open every
+                // image and lockfile in the repo looking for a `#!`.
+                ignore::Match::None if typed.extension().is_some() => continue,
+                ignore::Match::None => match shebang::language_for(&entry)? {
+                    Some(language) if self.selects(language) => language,
+                    _ => continue,
+                },
+            };
+
+            let canonical = entry
+                .canonicalize()
+                .wrap_err_with(|| format!("could not canonicalize `{}`", entry.display()))?;
+            if let Some(changed) = &changed {
+                if !changed.contains(&canonical) {
+                    run.stats.skip("not_git_changed");
+                    continue;
+                }
+            }
+
+            // files outside the root don't get a relative path (see below.)
+            let relative = root
+                .as_ref()
+                .and_then(|root| canonical.strip_prefix(root).ok())
+                .map(Path::to_path_buf);
+
+            if !seen.insert(canonical.clone()) {
+                tracing::debug!(path = ?entry, "skipping file we've already seen");
+                run.stats.skip("duplicate");
+                continue;
+            }
+
+            let label = overrides
+                .as_ref()
+                .and_then(|overrides| overrides.language_for(relative.as_deref().unwrap_or(&entry)))
+                .unwrap_or(detected);
+            let language = self
+                .language_alias
+                .iter()
+                .find(|alias| alias.label == label)
+                .map_or(label, |alias| alias.grammar.as_str())
+                .to_string();
+
+            let path = match relative {
+                Some(relative) => relative,
+                // we found this through arguments resolved against the root,
+                // so `entry` already has the root in it and `resolve` would
+                // add it again. Keep where the file really is instead.
+                None if root.is_some() => canonical,
+                None if self.no_normalize_paths => entry,
+                None => file_args::normalize(&entry),
+            };
+
+            languages.insert(language.clone());
+            paths.push(LanguageAndPath { language, path });
+        }
+
+        if self.sort_files {
+            paths.sort_by(|a, b| a.path.cmp(&b.path));
+        }
+
+        if let Some(sample) = self.sample {
+            let total = paths.len();
+            let mut rng = match self.seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy(),
+            };
+
+            paths.shuffle(&mut rng);
+            paths.truncate(sample);
+
+            // we don't want to load grammars for languages we didn't sample
+            languages = paths.iter().map(|path| path.language.clone()).collect();
+
+            tracing::info!("sampled {} of {total} files", paths.len());
+            run.stats.update(|counts| {
+                *counts.files_skipped.entry("not_sampled").or_default() += total - paths.len()
+            });
+        }
+
+        run.stats
+            .update(|counts| counts.files_discovered = paths.len());
+
+        Ok(LanguagesAndPaths { languages, paths })
+    }
+
+    /// Do `--language` and `--no-language` let this language through? We
+    /// only need this for languages we didn't get from file types, since
+    /// those already take the flags into account.
+    fn selects(&self, language: &str) -> bool {
+        (self.language.is_empty() || self.language.iter().any(|selected| selected == language))
+            && !self.no_language.iter().any(|negated| negated == language)
+    }
+
+    #[instrument]
+    pub fn slurp_all(&self, run: &RunContext) -> Result<cozo::DbInstance> {
+        let parsed = self.parse(run, None).wrap_err("could not parse files")?;
+        self.import(run, parsed)
+    }
+
+    /// Find and parse all our source files, without putting them in a
+    /// database yet. If `trees` is set, also send each file's tree to it as
+    /// nested JSON once the file is parsed.
+    #[instrument(skip(run, trees))]
+    pub fn parse(&self, run: &RunContext, trees: Option<&TreeSink>) -> Result<Parsed> {
+        let LanguagesAndPaths {
+            mut languages,
+            paths,
+        } = run
+            .timings
+            .time("discovery", || self.files(run))
+            .wrap_err("could not get files")?;
+
+        let include = self.include_paths();
+        let mut loader = self.loader(include.clone(), languages.len())?;
+
+        // check up front that we can find every grammar we need, so we don't
+        // fail one language at a time.
+        let mut missing: Vec<&String> = languages
+            .iter()
+            .filter(|language| loader.find_grammar(language).is_err())
+            .collect();
+        if !missing.is_empty() {
+            missing.sort();
+            bail!(
+                "could not find grammars for {} in any of the include paths ({}). Use `-i`/`--include` or `TREE_DB_LANGUAGE_SEARCH_PATH` to say where they are.",
+                missing
+                    .iter()
+                    .map(|language| format!("`{language}`"))
+                    .collect::<Vec<String>>()
+                    .join(", "),
+                include
+                    .iter()
+                    .map(|path| format!("`{}`", path.display()))
+                    .collect::<Vec<String>>()
+                    .join(", "),
+            );
+        }
+
+        let mut broken: Vec<(String, color_eyre::Report)> = Vec::new();
+        run.timings.time("grammar loading", || -> Result<()> {
+            for language in languages.drain() {
+                match loader.preload(language.clone()) {
+                    Ok(()) => (),
+                    Err(err) if self.skip_broken_grammars => {
+                        tracing::warn!(language, "could not load grammar, skipping its files");
+                        broken.push((language, err));
+                    }
+                    Err(err) => return Err(err).wrap_err("could not load language"),
+                }
+            }
+
+            Ok(())
+        })?;
+
+        match self.grammar_name_mismatch {
+            MismatchPolicy::Ignore => {}
+            MismatchPolicy::Warn => {
+                for mismatch in loader.mismatches() {
+                    tracing::warn!("{mismatch}");
+                }
+            }
+            MismatchPolicy::Fail => {
+                if let Some(mismatch) = loader.mismatches().first() {
+                    bail!("{mismatch}. Pass `--grammar-name-mismatch warn` to parse anyway.");
+                }
+            }
+        }
+
+        if let Some(policy) = &self.grammar_version_check {
+            let mut violations: Vec<String> = loader
+                .languages()
+                .filter(|(_, language)| !policy.allows(language.version()))
+                .map(|(name, language)| format!("`{name}` (version {})", language.version()))
+                .collect();
+
+            if !violations.is_empty() {
+                violations.sort();
+                bail!(
+                    "grammar versions must be {policy}, but these grammars are not: {}",
+                    violations.join(", ")
+                );
+            }
+        }
+
+        let db = if self.resume {
+            Some(
+                self.empty_db()
+                    .wrap_err("could not open the database we're resuming")?,
+            )
+        } else {
+            None
+        };
+
+        let previous = match &db {
+            Some(db) => self
+                .previous_hashes(db)
+                .wrap_err("could not read file hashes from the database we're resuming")?,
+            None => HashMap::new(),
+        };
+
+        // files that were in the database last time but that we didn't find
+        // now have been deleted (or moved), so their rows shouldn't stick
+        // around. When we're only looking at some files on purpose, though,
+        // the rest are still there; we just didn't pick them.
+        let picking = self.git_changed || self.modified_since.is_some() || self.sample.is_some();
+        if let Some(db) = db.as_ref().filter(|_| !picking) {
+            let found: HashSet<String> = paths
+                .iter()
+                .map(|file| file.path.to_string_lossy().into_owned())
+                .collect();
+
+            for path in previous.keys().filter(|path| !found.contains(*path)) {
+                tracing::debug!(path, "forgetting file that's gone");
+                self.forget(db, Path::new(path))
+                    .wrap_err_with(|| format!("could not remove old data for `{path}`"))?;
+            }
+        }
+
+        let db = db.map(Mutex::new);
+
+        let baseline = match &self.incremental_baseline {
+            Some(path) => Some(
+                self.baseline(path, &paths, &loader)
+                    .wrap_err("could not parse the incremental baseline")?,
+            ),
+            None => None,
+        };
+
+        let cache = self
+            .parser_cache()
+            .wrap_err("could not open parser cache")?;
+        let fingerprint = match &cache {
+            Some(_) => self.cache_fingerprint()?,
+            None => Vec::new(),
+        };
+
+        let context = ParseContext {
+            run,
+            loader: &loader,
+            broken: &broken,
+            previous: &previous,
+            baseline: baseline.as_ref(),
+            cache: cache.as_ref(),
+            fingerprint: &fingerprint,
+            trees,
+            db: db.as_ref(),
+        };
+
+        // `par_bridge` hands out files in the order we schedule them, rather
+        // than splitting the list up front like `par_iter`.
+        let mut files = run
+            .timings
+            .time("parsing", || {
+                self.schedule(&paths)
+                    .into_iter()
+                    .par_bridge()
+                    .map(|i| {
+                        Ok(self
+                            .parse_file(&paths[i], &context)?
+                            .map(|parsed| (i, parsed)))
+                    })
+                    .filter_map(Result::transpose)
+                    .collect::<Result<Vec<(usize, ParsedFile)>>>()
+            })
+            .wrap_err("failed to parse files")?;
+
+        // put the files back in the order we found them
+        files.sort_by_key(|(i, _)| *i);
+        let files: Vec<ParsedFile> = files.into_iter().map(|(_, file)| file).collect();
+
+        if self.resume {
+            tracing::info!(
+                "resuming: {} of {} files are new or changed",
+                files.len(),
+                paths.len()
+            );
+        }
+
+        for file in &files {
+            self.count(run, file);
+        }
+
+        if !broken.is_empty() {
+            broken.sort_by(|(a, _), (b, _)| a.cmp(b));
+            for (language, err) in &broken {
+                tracing::warn!(
+                    language,
+                    error = format!("{err:#}"),
+                    "skipped files in a language whose grammar could not be loaded"
+                );
+            }
+        }
+
+        // record which grammar ABI produced this export, for provenance
+        let languages = NamedRows {
+            headers: self
+                .relation("languages")
+                .expect("the languages relation is always in the schema")
+                .headers(),
+            rows: loader
+                .languages()
+                .map(|(name, language)| vec![json!(name), json!(language.version())])
+                .collect(),
+        };
+
+        Ok(Parsed {
+            languages,
+            files,
+            db: db.map(|db| db.into_inner().expect("a thread panicked while importing")),
+        })
+    }
+
+    /// Parse one of the files from `parse` (or get it from the cache.) We
+    /// return `None` for files we're skipping.
+    fn parse_file(
+        &self,
+        file: &LanguageAndPath,
+        context: &ParseContext,
+    ) -> Result<Option<ParsedFile>> {
+        let LanguageAndPath {
+            language: language_name,
+            path,
+        } = file;
+        let language = match context.loader.get(language_name) {
+            Some(language) => language,
+            None if context.broken.iter().any(|(name, _)| name == language_name) => {
+                context.run.stats.skip("broken_grammar");
+                return Ok(None);
+            }
+            None => bail!(
+                "could not get a language definition for `{language_name}`. Was it preloaded?"
+            ),
+        };
+
+        let mut exporter = FileExporter::new(self, language_name, language, path);
+        exporter.want_tree = context.trees.is_some();
+        exporter.baseline = context
+            .baseline
+            .filter(|baseline| &baseline.language == language_name);
+        exporter
+            .read_source()
+            .wrap_err_with(|| format!("could not read `{}`", path.display()))?;
+
+        let previous_hash = context.previous.get(&path.to_string_lossy().into_owned());
+        if previous_hash == Some(&exporter.hash) {
+            tracing::debug!(?path, "skipping unchanged file");
+            context.run.stats.skip("unchanged");
+            return Ok(None);
+        }
+
+        let cache_key = context.cache.map(|_| {
+            ParserCache::key(
+                path,
+                exporter.source.as_bytes(),
+                language_name,
+                context.loader.stamp(language_name).unwrap_or_default(),
+                language.version(),
+                context.fingerprint,
+            )
+        });
+        if let Some(cached) = context
+            .cache
+            .zip(cache_key.as_deref())
+            .and_then(|(cache, key)| cache.get(key))
+        {
+            if context.trees.is_none() || cached.tree.is_some() {
+                tracing::debug!(?path, "using cached rows");
+                context
+                    .run
+                    .stats
+                    .update(|counts| counts.files_from_cache += 1);
+                let parsed = ParsedFile {
+                    path: path.clone(),
+                    replaces: previous_hash.is_some(),
+                    relations: cached.relations,
+                    error_nodes: cached.error_nodes,
+                };
+                self.check_max_errors(context.run, &parsed)?;
+                if let (Some(sink), Some(tree)) = (context.trees, &cached.tree) {
+                    sink(path, tree)?;
+                }
+                self.import_as_parsed(&parsed, context)?;
+                return Ok(Some(parsed));
+            }
+        }
+
+        exporter
+            .slurp()
+            .wrap_err_with(|| format!("could not export from `{}`", path.display()))?;
+        context.run.stats.update(|counts| counts.files_parsed += 1);
+        let tree = exporter.tree.take();
+        let parsed = ParsedFile {
+            path: path.clone(),
+            replaces: previous_hash.is_some(),
+            error_nodes: exporter.error_nodes,
+            relations: exporter.into(),
+        };
+        self.check_max_errors(context.run, &parsed)?;
+        if let (Some(sink), Some(tree)) = (context.trees, &tree) {
+            sink(path, tree)?;
+        }
+
+        if let (Some(cache), Some(key)) = (context.cache, &cache_key) {
+            let cached = CachedFile {
+                relations: parsed.relations.clone(),
+                tree,
+                error_nodes: parsed.error_nodes,
+            };
+            // the cache is only an optimization, so failing to write
+            // to it shouldn't stop the export.
+            if let Err(err) = cache.put(key, &cached) {
+                tracing::warn!(?path, "could not write to parser cache: {err:#}");
+            }
+        }
+
+        self.import_as_parsed(&parsed, context)?;
+
+        Ok(Some(parsed))
+    }
+
+    /// With --resume, put a file into the database right away instead of
+    /// waiting for every file to be parsed.
+    fn import_as_parsed(&self, parsed: &ParsedFile, context: &ParseContext) -> Result<()> {
+        let db = match context.db {
+            Some(db) => db.lock().expect("a thread panicked while importing"),
+            None => return Ok(()),
+        };
+
+        self.import_file(&db, &parsed.path, parsed.replaces, parsed.relations.clone())
+            .wrap_err_with(|| format!("could not import `{}`", parsed.path.display()))
+    }
+
+    /// The order to parse `paths` in, as indexes. We start with the biggest
+    /// files: when one huge file is among lots of small ones, parsing it
+    /// last would leave every other thread idle while it finishes. With
+    /// --sort-files, we stick to path order instead, so runs are repeatable.
+    fn schedule(&self, paths: &[LanguageAndPath]) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..paths.len()).collect();
+        if self.sort_files {
+            return order;
+        }
+
+        // files we can't stat will fail soon enough when we read them, so
+        // it doesn't matter where they go.
+        let sizes: Vec<u64> = paths
+            .iter()
+            .map(|file| {
+                std::fs::metadata(self.resolve(&file.path)).map_or(0, |metadata| metadata.len())
+            })
+            .collect();
+        order.sort_by_key(|i| std::cmp::Reverse(sizes[*i]));
+
+        order
+    }
+
+    /// Put parsed files into a new database.
+    #[instrument(skip(run, parsed))]
+    pub fn import(&self, run: &RunContext, parsed: Parsed) -> Result<cozo::DbInstance> {
+        run.timings.time("import", || self.import_untimed(parsed))
+    }
+
+    fn import_untimed(&self, parsed: Parsed) -> Result<cozo::DbInstance> {
+        let languages = parsed.languages();
+
+        // with --resume, the files went in as we parsed them.
+        if let Some(db) = parsed.db {
+            db.import_relations(languages).into_report()?;
+            return Ok(db);
+        }
+
+        let db = self.empty_db().wrap_err("could not set up empty Cozo DB")?;
+
+        db.import_relations(languages).into_report()?;
+
+        for file in parsed.files {
+            self.import_file(&db, &file.path, file.replaces, file.relations)?;
+        }
+
+        Ok(db)
+    }
+
+    /// Import one file's rows, first removing what was there before if it
+    /// `replaces` an earlier version.
+    fn import_file(
+        &self,
+        db: &cozo::DbInstance,
+        path: &Path,
+        replaces: bool,
+        relations: BTreeMap<String, NamedRows>,
+    ) -> Result<()> {
+        if replaces {
+            self.forget(db, path)
+                .wrap_err_with(|| format!("could not remove old data for `{}`", path.display()))?;
+        }
+
+        self.import_relations(db, relations)
+    }
+
+    /// Import rows into `db`, in chunks if we were given a --chunk-size.
+    fn import_relations(
+        &self,
+        db: &cozo::DbInstance,
+        relations: BTreeMap<String, NamedRows>,
+    ) -> Result<()> {
+        for batch in self.import_batches(relations) {
+            let names: Vec<&String> = batch.keys().collect();
+            let context = format!("could not import rows into {names:?}");
+
+            db.import_relations(batch).into_report().wrap_err(context)?;
+        }
+
+        Ok(())
+    }
+
+    /// Split `relations` into the batches `import_relations` hands to Cozo:
+    /// everything at once, or with a --chunk-size, that many rows of one
+    /// relation at a time.
+    ///
+    /// Cozo makes its own copy of everything in an import before writing
+    /// it, so importing a chunk at a time keeps that copy small. Our rows
+    /// are moved into each chunk, so they're freed as they go in too.
+    fn import_batches(
+        &self,
+        relations: BTreeMap<String, NamedRows>,
+    ) -> Vec<BTreeMap<String, NamedRows>> {
+        let chunk_size = match self.chunk_size {
+            Some(chunk_size) => chunk_size.get(),
+            None => return vec![relations],
+        };
+
+        let mut batches = Vec::new();
+        for (name, data) in relations {
+            let mut rows = data.rows.into_iter().peekable();
+            while rows.peek().is_some() {
+                let chunk = NamedRows {
+                    headers: data.headers.clone(),
+                    rows: rows.by_ref().take(chunk_size).collect(),
+                };
+
+                batches.push(BTreeMap::from([(name.clone(), chunk)]));
+            }
+        }
+
+        batches
+    }
+
+    /// The hash of every file in the database we're resuming, by path.
+    fn previous_hashes(&self, db: &cozo::DbInstance) -> Result<HashMap<String, i64>> {
+        let rows = db
+            .run_script("?[path, hash] := *files{path, hash}", BTreeMap::new())
+            .into_report()?;
+
+        Ok(rows
+            .rows
+            .into_iter()
+            .filter_map(|row| match (&row[0], &row[1]) {
+                (Value::String(path), Value::Number(hash)) => Some((path.clone(), hash.as_i64()?)),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// Remove everything we know about a file, so we can re-import it.
+    fn forget(&self, db: &cozo::DbInstance, path: &Path) -> Result<()> {
+        for relation in self.schema() {
+            if !relation.columns().any(|column| column.name == "path") {
+                continue;
+            }
+
+            let keys: Vec<&str> = relation.keys().map(|column| column.name).collect();
+            let mut bindings = keys.clone();
+            if !bindings.contains(&"path") {
+                bindings.push("path");
+            }
+
+            let script = format!(
+                "?[{keys}] := *{name}{{{bindings}}}, path = $path\n:rm {name} {{{keys}}}",
+                keys = keys.join(", "),
+                bindings = bindings.join(", "),
+                name = relation.name,
+            );
+
+            db.run_script(&script, BTreeMap::from([("path".into(), json!(path))]))
+                .into_report()?;
+        }
+
+        Ok(())
+    }
+
+    /// Every node kind and field name `language`'s grammar can produce, the
+    /// way we'd export them. Grammars can have hidden rules (and several ids
+    /// for the same kind), so this is the set of distinct visible kinds
+    /// rather than every symbol.
+    pub fn node_types(&self, language_name: &str) -> Result<Value> {
+        let mut loader = self.loader(self.include_paths(), 1)?;
+        loader
+            .preload(language_name.to_string())
+            .wrap_err("could not load language")?;
+        let language = loader.get(language_name).ok_or_else(|| {
+            eyre!("could not get a language definition for `{language_name}`. Was it preloaded?")
+        })?;
+
+        let kinds: BTreeSet<(&str, bool)> = (0..language.node_kind_count())
+            .filter_map(|id| {
+                let id = u16::try_from(id).ok()?;
+                if !language.node_kind_is_visible(id) {
+                    return None;
+                }
+                Some((
+                    language.node_kind_for_id(id)?,
+                    language.node_kind_is_named(id),
+                ))
+            })
+            .collect();
+
+        let fields: BTreeSet<&str> = (1..=language.field_count())
+            .filter_map(|id| language.field_name_for_id(u16::try_from(id).ok()?))
+            .collect();
+
+        Ok(json!({
+            "language": language_name,
+            "abi_version": language.version(),
+            "kinds": kinds
+                .into_iter()
+                .map(|(kind, named)| json!({
+                    "kind": qualified_kind(self, language_name, kind),
+                    "named": named,
+                }))
+                .collect::<Vec<Value>>(),
+            "fields": fields,
+        }))
+    }
+
+    /// Parse the one file these options match and show its tree the way
+    /// we'd export it, one node per line.
+    pub fn dump_ast(&self) -> Result<String> {
+        let LanguagesAndPaths { paths, .. } = self
+            .files(&RunContext::default())
+            .wrap_err("could not get files")?;
+        let file = match paths.as_slice() {
+            [file] => file,
+            [] => bail!("no files matched"),
+            _ => bail!(
+                "we can only dump one file at a time, but {} matched",
+                paths.len()
+            ),
+        };
+
+        let mut loader = self.loader(self.include_paths(), 1)?;
+        loader
+            .preload(file.language.clone())
+            .wrap_err("could not load language")?;
+        let language = loader.get(&file.language).ok_or_else(|| {
+            eyre!(
+                "could not get a language definition for `{}`. Was it preloaded?",
+                file.language
+            )
+        })?;
+
+        let mut exporter = FileExporter::new(self, &file.language, language, &file.path);
+        exporter
+            .read_source()
+            .wrap_err_with(|| format!("could not read `{}`", file.path.display()))?;
+        exporter
+            .slurp()
+            .wrap_err_with(|| format!("could not parse `{}`", file.path.display()))?;
+
+        Ok(exporter.dump())
+    }
+
+    /// Log the `--timing` breakdown, if we were asked for it.
+    pub fn report_timings(&self, run: &RunContext) {
+        if self.timing {
+            run.timings.log();
+        }
+    }
+
+    /// Write `--stats-json`, if we were asked to.
+    pub fn report_stats(&self, run: &RunContext) -> Result<()> {
+        let path = match &self.stats_json {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let json = run.stats.to_json(run.timings.total());
+        std::fs::write(path, format!("{json}\n"))
+            .wrap_err_with(|| format!("could not write stats to `{}`", path.display()))
+    }
+
+    /// Add `file`'s syntax errors to the running total, and stop the run if
+    /// that puts us over --max-errors.
+    fn check_max_errors(&self, run: &RunContext, file: &ParsedFile) -> Result<()> {
+        let max = match self.max_errors {
+            Some(max) => max,
+            None => return Ok(()),
+        };
+
+        let errors = file.error_nodes;
+        if errors == 0 {
+            return Ok(());
+        }
+
+        let seen = run.errors_seen.fetch_add(errors, Ordering::Relaxed) + errors;
+        if seen >= max {
+            return Err(MaxErrorsReached { max }).wrap_err_with(|| {
+                format!(
+                    "stopped at `{}` after seeing {seen} syntax errors",
+                    file.path.display()
+                )
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Add up nodes, edges, and errors in a file we're about to import. We
+    /// go by the rows rather than the exporter, since files from the parser
+    /// cache never had one.
+    fn count(&self, run: &RunContext, file: &ParsedFile) {
+        let column = |relation: &str, name: &str| -> Option<(&NamedRows, usize)> {
+            let rows = file.relations.get(relation)?;
+            let index = rows.headers.iter().position(|header| header == name)?;
+            Some((rows, index))
+        };
+
+        run.stats.update(|counts| {
+            if let Some(nodes) = file.relations.get("nodes") {
+                counts.nodes += nodes.rows.len();
+            }
+            counts.error_nodes += file.error_nodes;
+
+            if let Some(edges) = file.relations.get("edges") {
+                counts.edges += edges.rows.len();
+            }
+
+            if let Some((files, status)) = column("files", "parse_status") {
+                counts.files_with_errors += files
+                    .rows
+                    .iter()
+                    .filter(|row| row[status] == json!("has_errors"))
+                    .count();
+            }
+
+            if let Some((files, language)) = column("files", "language") {
+                for row in &files.rows {
+                    if let Value::String(language) = &row[language] {
+                        *counts.languages.entry(language.clone()).or_default() += 1;
+                    }
+                }
+            }
+        });
+    }
+
+    /// Parse the file for `--incremental-baseline`. We use the language we
+    /// already decided on for it, so it has to be one of our files.
+    fn baseline(
+        &self,
+        path: &Path,
+        paths: &[LanguageAndPath],
+        loader: &Loader,
+    ) -> Result<Baseline> {
+        let path = &self.resolve(path);
+        let canonical = path
+            .canonicalize()
+            .wrap_err_with(|| format!("could not canonicalize `{}`", path.display()))?;
+
+        let language_name = match paths.iter().find(|candidate| {
+            self.resolve(&candidate.path)
+                .canonicalize()
+                .map_or(false, |candidate| candidate == canonical)
+        }) {
+            Some(found) => &found.language,
+            None => bail!(
+                "`{}` isn't one of the files we're exporting, so we don't know what language it's in",
+                path.display()
+            ),
+        };
+
+        let language = loader.get(language_name).ok_or_else(|| {
+            eyre!("could not get a language definition for `{language_name}`. Was it preloaded?")
+        })?;
+
+        let source =
+            std::fs::read(path).wrap_err_with(|| format!("could not read `{}`", path.display()))?;
+
+        let mut parser = Parser::new();
+        parser
+            .set_language(language)
+            .wrap_err("could not set parser language")?;
+        let tree = match parser.parse(&source, None) {
+            Some(tree) => tree,
+            None => bail!("internal error: parser did not return a tree"),
+        };
+
+        Ok(Baseline::new(language_name.clone(), source, tree))
+    }
+
+    /// Where to find `path` on disk, taking --root into account.
+    fn resolve(&self, path: &Path) -> PathBuf {
+        match &self.root {
+            Some(root) => root.join(path),
+            None => path.to_path_buf(),
+        }
+    }
+
+    /// The parser cache, if we're using it. `--clear-cache` empties it even
+    /// if we aren't.
+    fn parser_cache(&self) -> Result<Option<ParserCache>> {
+        let enabled = self.parser_cache && !self.no_cache;
+        if !enabled && !self.clear_cache {
+            return Ok(None);
+        }
+
+        let cache = ParserCache::open(self.cache_dir.clone())?;
+        if self.clear_cache {
+            cache.clear().wrap_err("could not clear parser cache")?;
+        }
+
+        Ok(if enabled { Some(cache) } else { None })
+    }
+
+    /// Every option that could change the rows we export for a single file
+    /// (so everything but `CACHE_IGNORES`), so that changing any of them
+    /// misses the parser cache.
+    fn cache_fingerprint(&self) -> Result<Vec<u8>> {
+        let mut options = match serde_json::to_value(self)
+            .wrap_err("could not serialize options for the parser cache")?
+        {
+            Value::Object(options) => options,
+            other => {
+                bail!("internal error: expected options to serialize to an object, but got {other}")
+            }
+        };
+
+        for ignored in CACHE_IGNORES {
+            options.remove(*ignored);
+        }
+
+        serde_json::to_vec(&options).wrap_err("could not serialize options for the parser cache")
+    }
+
+    fn loader(&self, include: Vec<PathBuf>, size: usize) -> Result<Loader> {
+        let mut loader = Loader::with_capacity(include, size);
+        for grammar in &self.grammar {
+            loader.pin(grammar.name.clone(), grammar.path.clone());
+        }
+        if self.grammar_search_recursive {
+            loader.search_recursively();
+        }
+        if let Some(path) = &self.grammar_manifest {
+            loader.use_manifest(Manifest::load(path).wrap_err("could not load grammar manifest")?);
+        }
+        Ok(loader)
+    }
+
+    /// Where to look for grammars: `-i` if given, then
+    /// `TREE_DB_LANGUAGE_SEARCH_PATH`, then the current directory.
+    fn include_paths(&self) -> Vec<PathBuf> {
+        if !self.include.is_empty() {
+            return self.include.clone();
+        }
+
+        let from_env: Vec<PathBuf> = std::env::var_os("TREE_DB_LANGUAGE_SEARCH_PATH")
+            .map(|paths| {
+                std::env::split_paths(&paths)
+                    .filter(|path| !path.as_os_str().is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        if from_env.is_empty() {
+            vec![PathBuf::from(".")]
+        } else {
+            from_env
+        }
+    }
+
+    /// The relations we'll export with these options.
+    pub fn schema(&self) -> Vec<Relation> {
+        let path = Column::new("path", ColumnType::String);
+        let id = Column::new("id", ColumnType::Int);
+
+        let mut locations = match self.location_format {
+            LocationFormat::Columns => vec![
+                Column::new("start_byte", ColumnType::Int),
+                Column::new("start_row", ColumnType::Int),
+                Column::new("start_column", ColumnType::Int),
+                Column::new("end_byte", ColumnType::Int),
+                Column::new("end_row", ColumnType::Int),
+                Column::new("end_column", ColumnType::Int),
+            ],
+            LocationFormat::Packed => vec![Column::new("location", ColumnType::String)],
+        };
+        if self.char_offsets {
+            locations.push(Column::new("start_char", ColumnType::Int));
+            locations.push(Column::new("end_char", ColumnType::Int));
+        }
+
+        let mut nodes = vec![
+            Column::new("kind", ColumnType::String),
+            Column::new("is_error", ColumnType::Bool),
+            Column::new("source", ColumnType::NullableString),
+            Column::new("descendant_count", ColumnType::Int),
+        ];
+        if self.source_hash {
+            nodes.push(Column::new("source_hash", ColumnType::Int));
+        }
+        if self.normalize_source {
+            nodes.push(Column::new("source_normalized", ColumnType::NullableString));
+        }
+        if self.node_uids {
+            nodes.push(Column::new("node_uid", ColumnType::Int));
+        }
+        if self.token_column {
+            nodes.push(Column::new("token", ColumnType::NullableString));
+        }
+        if self.include_bytes {
+            nodes.push(Column::new("source_bytes", ColumnType::NullableString));
+        }
+        if self.linked_tree {
+            nodes.extend([
+                Column::new("first_child", ColumnType::NullableInt),
+                Column::new("last_child", ColumnType::NullableInt),
+                Column::new("next_sibling", ColumnType::NullableInt),
+            ]);
+        }
+        if self.wide {
+            nodes.extend(locations.iter().copied());
+        }
+
+        let mut relations = vec![Relation::new("nodes", vec![path, id], nodes)];
+
+        if !self.wide && !self.no_locations {
+            relations.push(Relation::new("node_locations", vec![path, id], locations));
+        }
+
+        relations.extend([
+            Relation::new(
+                "edges",
+                vec![
+                    path,
+                    Column::new("parent", ColumnType::Int),
+                    Column::new("child", ColumnType::Int),
+                    Column::new("field", ColumnType::NullableString),
+                ],
+                {
+                    let mut values = Vec::new();
+                    if self.edge_offsets {
+                        values.push(Column::new("child_start_offset", ColumnType::Int));
+                    }
+                    if self.field_ids {
+                        values.push(Column::new("field_id", ColumnType::NullableInt));
+                    }
+                    if self.has_field {
+                        values.push(Column::new("has_field", ColumnType::Bool));
+                    }
+                    if self.denormalize_edges {
+                        values.push(Column::new("parent_kind", ColumnType::String));
+                        values.push(Column::new("child_kind", ColumnType::String));
+                    }
+                    if self.node_uids {
+                        values.push(Column::new("parent_uid", ColumnType::Int));
+                        values.push(Column::new("child_uid", ColumnType::Int));
+                    }
+                    values
+                },
+            )
+            // the keys start with `parent`, so this is the other way around
+            .with_index(&["path", "child"]),
+            Relation::new(
+                "files",
+                vec![path],
+                vec![
+                    Column::new("language", ColumnType::String),
+                    Column::new("parse_status", ColumnType::String),
+                    Column::new("hash", ColumnType::Int),
+                ],
+            ),
+            Relation::new("subtree_roots", vec![path, id], vec![]),
+            Relation::new(
+                "languages",
+                vec![Column::new("name", ColumnType::String)],
+                vec![Column::new("abi_version", ColumnType::Int)],
+            ),
+        ]);
+
+        if self.count_kinds {
+            relations.push(Relation::new(
+                "kind_counts",
+                vec![path, Column::new("kind", ColumnType::String)],
+                vec![Column::new("count", ColumnType::Int)],
+            ));
+        }
+
+        if self.on_error == ErrorPolicy::Record {
+            relations.push(Relation::new(
+                "errors",
+                vec![path, id],
+                vec![
+                    Column::new("is_missing", ColumnType::Bool),
+                    Column::new("start_row", ColumnType::Int),
+                    Column::new("start_column", ColumnType::Int),
+                ],
+            ));
+        }
+
+        if self.tokens {
+            relations.push(Relation::new(
+                "tokens",
+                vec![path, id],
+                vec![
+                    Column::new("text", ColumnType::NullableString),
+                    Column::new("start_byte", ColumnType::Int),
+                    Column::new("end_byte", ColumnType::Int),
+                    Column::new("kind", ColumnType::String),
+                ],
+            ));
+        }
+
+        if self.sibling_edges {
+            relations.push(
+                Relation::new(
+                    "sibling_edges",
+                    vec![path, Column::new("node", ColumnType::Int)],
+                    vec![Column::new("next", ColumnType::Int)],
+                )
+                .with_index(&["path", "next"]),
+            );
+        }
+
+        if self.error_context {
+            relations.push(
+                Relation::new(
+                    "error_context",
+                    vec![path, Column::new("error_id", ColumnType::Int)],
+                    vec![
+                        Column::new("ancestor_id", ColumnType::Int),
+                        Column::new("ancestor_kind", ColumnType::String),
+                    ],
+                )
+                .with_index(&["path", "ancestor_id"]),
+            );
+        }
+
+        // uids are how tools that can't join on `(path, id)` get from one
+        // relation to another, so they need to be fast to look up.
+        if self.node_uids {
+            relations = relations
+                .into_iter()
+                .map(|relation| match relation.name {
+                    "nodes" => relation.with_index(&["node_uid"]),
+                    "edges" => relation
+                        .with_index(&["parent_uid"])
+                        .with_index(&["child_uid"]),
+                    _ => relation,
+                })
+                .collect();
+        }
+
+        match self.relation_key_mode {
+            KeyMode::PathId => relations,
+            KeyMode::Content => relations
+                .into_iter()
+                .map(|relation| match relation.name {
+                    "nodes" | "node_locations" | "subtree_roots" => relation.with_keys(&["id"]),
+                    "edges" => relation.with_keys(&["parent", "child", "field"]),
+                    _ => relation,
+                })
+                .collect(),
+        }
+    }
+
+    fn relation(&self, name: &str) -> Option<Relation> {
+        self.schema()
+            .into_iter()
+            .find(|relation| relation.name == name)
+    }
+
+    /// The names of all the relations we'll export, for `export_relations`.
+    pub fn relation_names(&self) -> Vec<&'static str> {
+        self.schema()
+            .into_iter()
+            .map(|relation| relation.name)
+            .collect()
+    }
+
+    pub fn empty_db(&self) -> Result<cozo::DbInstance> {
+        self.empty_db_in(self.engine, self.engine_path.as_deref())
+    }
+
+    fn empty_db_in(&self, engine: Engine, path: Option<&Path>) -> Result<cozo::DbInstance> {
+        let path = match path {
+            Some(path) => path.display().to_string(),
+            None if engine == Engine::Mem => String::new(),
+            None => bail!(
+                "the {} engine needs a path. Is there a misconfiguration or bug?",
+                engine.name()
+            ),
+        };
+
+        let resuming = self.resume && engine != Engine::Mem && Path::new(&path).exists();
+
+        // Cozo uses miette for error handling, which can't be used with
+        // color_eyre directly. `into_report` renders them into something we
+        // can use instead.
+        let db = cozo::DbInstance::new(engine.name(), &path, "").into_report()?;
+
+        // the relations are already there from last time
+        if resuming {
+            return Ok(db);
+        }
+
+        db.run_script(&schema::to_cozo(&self.schema()), BTreeMap::new())
+            .into_report()?;
+
+        Ok(db)
+    }
+}
+
+#[derive(Debug)]
+pub struct FileExporter<'path> {
+    config: &'path SourceConfig,
+    language_name: &'path str,
+    language: Language,
+    parse_status: ParseStatus,
+
+    path: &'path Path,
+    source: Source,
+
+    nodes: Vec<ExportableNode<'path>>,
+    locations: Vec<ExportableNodeLocation<'path>>,
+    edges: Vec<ExportableEdge<'path>>,
+    subtree_roots: Vec<usize>,
+
+    /// Error and missing nodes, for `--on-error record`.
+    errors: Vec<RecordedError>,
+
+    /// Rendered errors for --pretty-errors, in source order. We print them
+    /// all at once when we're done with the file, so that files parsed at
+    /// the same time don't get their errors mixed together.
+    pretty_error_messages: Vec<String>,
+
+    /// Each node and the next child of the same parent, for
+    /// `--sibling-edges`.
+    siblings: Vec<(usize, usize)>,
+
+    /// Each error node's nearest named ancestor (and its kind), for
+    /// `--error-context`.
+    error_contexts: Vec<(usize, usize, &'static str)>,
+
+    /// Content hashes for each node id, for `--relation-key-mode content`.
+    content_ids: HashMap<usize, usize>,
+
+    /// How many nodes of each kind we exported, for `--count-kinds`.
+    kind_counts: BTreeMap<&'static str, usize>,
+
+    /// Whether to build `tree`, and the nested tree for `tree-json` output.
+    want_tree: bool,
+    tree: Option<Value>,
+
+    /// A hash of the whole file, so we can tell if it's changed later.
+    hash: i64,
+
+    /// How many error and missing nodes are in the tree, whether or not we
+    /// export them.
+    error_nodes: usize,
+
+    /// Whether the file is empty or all whitespace, and whether to show
+    /// errors with --pretty-errors. Set at the start of `slurp`.
+    is_blank: bool,
+    pretty_errors: bool,
+
+    /// The bytes selected with `--range`, if any. Set at the start of
+    /// `slurp`.
+    range: Option<std::ops::Range<usize>>,
+
+    /// A tree to parse incrementally from, for `--incremental-baseline`.
+    baseline: Option<&'path Baseline>,
+}
+
+/// A node to visit, paired with whether it's inside a subtree we're
+/// exporting, and the edge that leads to it: the nearest exported ancestor
+/// and the field name, if any.
+type Todo<'tree> = (
+    Node<'tree>,
+    bool,
+    Option<(Node<'tree>, Option<&'static str>)>,
+);
+
+/// Everything we collected while walking (part of) a tree.
+#[derive(Debug, Default)]
+struct Walked<'path> {
+    nodes: Vec<ExportableNode<'path>>,
+    locations: Vec<ExportableNodeLocation<'path>>,
+    edges: Vec<ExportableEdge<'path>>,
+    subtree_roots: Vec<usize>,
+    kind_counts: BTreeMap<&'static str, usize>,
+    content_ids: HashMap<usize, usize>,
+    errors: Vec<RecordedError>,
+    pretty_error_messages: Vec<String>,
+}
+
+#[derive(Debug)]
+struct RecordedError {
+    id: usize,
+    is_missing: bool,
+    start: tree_sitter::Point,
+}
+
+impl<'path> FileExporter<'path> {
+    fn new(
+        config: &'path SourceConfig,
+        language_name: &'path str,
+        language: Language,
+        path: &'path Path,
+    ) -> Self {
+        Self {
+            config,
+            language_name,
+            language,
+            parse_status: ParseStatus::Ok,
+            path,
+            // TODO: these capacities are really a shot in the dark. It's
+            // probably worth measuring what's typical and then adjusting them.
+            source: Source::Read(String::with_capacity(2 ^ 10)),
+            nodes: Vec::with_capacity(2 ^ 10),
+            locations: Vec::with_capacity(2 ^ 10),
+            edges: Vec::with_capacity(2 ^ 10),
+            subtree_roots: Vec::new(),
+            errors: Vec::new(),
+            pretty_error_messages: Vec::new(),
+            siblings: Vec::new(),
+            error_contexts: Vec::new(),
+            content_ids: HashMap::new(),
+            kind_counts: BTreeMap::new(),
+            want_tree: false,
+            tree: None,
+            hash: 0,
+            error_nodes: 0,
+            is_blank: false,
+            pretty_errors: false,
+            range: None,
+            baseline: None,
+        }
+    }
+
+    #[instrument(skip(self), fields(path = ?self.path))]
+    fn slurp(&mut self) -> Result<()> {
+        let mut parser = Parser::new();
+        parser
+            .set_language(self.language)
+            // an ABI mismatch is by far the most common reason for this to
+            // fail, so say what we'd need to fix it.
+            .wrap_err_with(|| {
+                format!(
+                    "could not set parser language. The `{}` grammar uses ABI version {}, but this tree-db supports versions {} through {}. Try recompiling the grammar.",
+                    self.language_name,
+                    self.language.version(),
+                    tree_sitter::MIN_COMPATIBLE_LANGUAGE_VERSION,
+                    tree_sitter::LANGUAGE_VERSION,
+                )
+            })?;
+
+        let old_tree = self
+            .baseline
+            .map(|baseline| baseline.tree_for(self.source.as_bytes()));
+
+        let tree = match parser.parse(self.source.as_bytes(), old_tree.as_ref()) {
+            Some(tree) => tree,
+            None => bail!("internal error: parser did not return a tree"),
+        };
+
+        // Empty and whitespace-only files (think `__init__.py`) are common in
+        // real repos. Some grammars flag the root of an empty tree as an
+        // error, but there's nothing for the user to fix, so don't warn.
+        let is_blank = self.source.as_bytes().iter().all(u8::is_ascii_whitespace);
+
+        if tree.root_node().has_error() && !is_blank {
+            self.parse_status = ParseStatus::HasErrors;
+            self.error_nodes = count_errors(tree.root_node());
+
+            if self.config.on_error == ErrorPolicy::Fail {
+                match first_error(tree.root_node()) {
+                    Some(node) => bail!(
+                        "found a syntax error at {}:{}",
+                        node.start_position().row,
+                        node.start_position().column
+                    ),
+                    None => bail!("found a syntax error"),
+                }
+            }
+        }
+
+        self.is_blank = is_blank;
+        self.pretty_errors = self.config.pretty_errors && std::io::stderr().is_terminal();
+        self.range = match self
+            .config
+            .range
+            .iter()
+            .find(|selector| selector.matches(self.config, self.path))
+        {
+            Some(selector) => Some(selector.bytes(self.source.as_bytes())?),
+            None => None,
+        };
+
+        let root = tree.root_node();
+        let start = (root, self.config.root_node_kind.is_none(), None);
+
+        let walked = if self.config.parallel_subtrees && root.child_count() > 1 {
+            let mut out = Walked::default();
+            let children = self.visit(start, &mut root.walk(), &mut out);
+
+            // nodes can't be sent between threads, so we send each child's
+            // position instead and look it up again on the other side. The
+            // only node a child can be attached to here is the root.
+            let pending: Vec<(usize, bool, Option<Option<&'static str>>)> = children
+                .into_iter()
+                .enumerate()
+                .map(|(i, (_, in_subtree, incoming))| {
+                    (i, in_subtree, incoming.map(|(_, field)| field))
+                })
+                .collect();
+
+            let this = &*self;
+            let subtrees: Vec<Walked<'path>> = pending
+                .into_par_iter()
+                .map(|(i, in_subtree, field)| {
+                    // copying a tree is cheap, and tree-sitter only promises
+                    // that separate copies are safe to use from different
+                    // threads.
+                    let tree = tree.clone();
+                    let root = tree.root_node();
+                    let child = root
+                        .child(i)
+                        .expect("the root should still have the same children");
+                    this.walk((child, in_subtree, field.map(|field| (root, field))))
+                })
+                .collect();
+
+            std::iter::once(out).chain(subtrees).collect()
+        } else {
+            vec![self.walk(start)]
+        };
+
+        for walked in walked {
+            self.nodes.extend(walked.nodes);
+            self.locations.extend(walked.locations);
+            self.edges.extend(walked.edges);
+            self.subtree_roots.extend(walked.subtree_roots);
+            self.content_ids.extend(walked.content_ids);
+            self.errors.extend(walked.errors);
+            self.pretty_error_messages
+                .extend(walked.pretty_error_messages);
+            for (kind, count) in walked.kind_counts {
+                *self.kind_counts.entry(kind).or_default() += count;
+            }
+        }
+
+        self.report_pretty_errors();
+        self.count_descendants();
+
+        if self.config.char_offsets {
+            self.count_chars();
+        }
+
+        if self.config.error_context {
+            self.find_error_contexts();
+        }
+
+        if self.config.sibling_edges {
+            self.find_siblings();
+        }
+
+        if self.config.linked_tree {
+            self.link_tree();
+        }
+
+        // this has to happen before we replace ids, since content ids aren't
+        // unique within a file.
+        if self.want_tree {
+            self.tree = Some(self.tree_json());
+        }
+
+        if self.config.relation_key_mode == KeyMode::Content {
+            let ids = std::mem::take(&mut self.content_ids);
+            self.remap_ids(&ids);
+        } else if self.config.stable_ids {
+            self.renumber();
+
+            // remapping has gotten edges out of step with nodes before, and
+            // the result imports fine but makes queries quietly wrong.
+            if cfg!(debug_assertions) {
+                self.check_renumbered_tree()
+                    .wrap_err("internal error: renumbering ids broke the tree")?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Walk the tree from `start` in pre-order, collecting everything we'll
+    /// export.
+    fn walk<'tree>(&self, start: Todo<'tree>) -> Walked<'path> {
+        let mut out = Walked::default();
+        let mut cursor = start.0.walk();
+
+        let mut todo = vec![start];
+        while let Some(next) = todo.pop() {
+            // we push children in reverse so that we pop (and therefore
+            // visit) them in source order.
+            let children = self.visit(next, &mut cursor, &mut out);
+            todo.extend(children.into_iter().rev());
+        }
+
+        out
+    }
+
+    /// Export a single node (if we should), and return its children in
+    /// source order, ready to visit next.
+    fn visit<'tree>(
+        &self,
+        (node, in_subtree, incoming): Todo<'tree>,
+        cursor: &mut TreeCursor<'tree>,
+        out: &mut Walked<'path>,
+    ) -> Vec<Todo<'tree>> {
+        // a node's range always covers its children's, so if we skip
+        // everything outside the range, whatever's left is still connected
+        // to the root.
+        if let Some(range) = &self.range {
+            // an empty range (like a cursor position) selects the nodes
+            // around that point.
+            let overlaps =
+                node.start_byte() < range.end.max(range.start + 1) && node.end_byte() > range.start;
+            if !overlaps && node.parent().is_some() {
+                return Vec::new();
+            }
+        }
+
+        let warn = self.config.on_error == ErrorPolicy::Warn && !self.is_blank;
+        if warn && self.pretty_errors && (node.is_error() || node.is_missing()) {
+            out.pretty_error_messages.push(self.pretty_error(&node));
+        } else if warn && node.is_error() {
+            let range = node.range();
+            tracing::warn!(
+                "`{}` contains an error at {}:{}",
+                self.path.display(),
+                range.start_point.row,
+                range.start_point.column,
+            )
+        }
+
+        let children: Vec<Node<'tree>> = node.children(cursor).collect();
+
+        let in_subtree = in_subtree || self.config.root_node_kind.as_deref() == Some(node.kind());
+        if !in_subtree {
+            return children
+                .into_iter()
+                .map(|child| (child, false, None))
+                .collect();
+        }
+
+        // when we skip a node, its children attach to whatever it was
+        // attached to instead.
+        if (self.config.named_only && !node.is_named())
+            || self
+                .config
+                .exclude_kind
+                .iter()
+                .any(|kind| kind == node.kind())
+        {
+            return children
+                .into_iter()
+                .map(|child| (child, true, incoming.map(|(parent, _)| (parent, None))))
+                .collect();
+        }
+
+        let mut exportable = ExportableNode::from(self.path, &node);
+        if self.config.source_hash {
+            exportable.source_hash = fnv1a(self.source.bytes(node.start_byte()..node.end_byte()));
+        }
+        out.nodes.push(exportable);
+
+        if self.config.count_kinds {
+            *out.kind_counts.entry(node.kind()).or_default() += 1;
+        }
+
+        if self.config.on_error == ErrorPolicy::Record
+            && (node.is_error() || node.is_missing())
+            && !self.is_blank
+        {
+            out.errors.push(RecordedError {
+                id: node.id(),
+                is_missing: node.is_missing(),
+                start: node.start_position(),
+            });
+        }
+
+        if self.config.relation_key_mode == KeyMode::Content {
+            out.content_ids.insert(
+                node.id(),
+                content_id(
+                    node.kind(),
+                    self.source.bytes(node.start_byte()..node.end_byte()),
+                ),
+            );
+        }
+        if !self.config.no_locations {
+            out.locations
+                .push(ExportableNodeLocation::from(self.path, &node));
+        }
+
+        match incoming {
+            Some((parent, field)) => out.edges.push(ExportableEdge {
+                path: self.path,
+                parent: parent.id(),
+                child: node.id(),
+                field,
+                field_id: field.and_then(|name| self.language.field_id_for_name(name)),
+                // `parent` is the nearest ancestor we export, so it should
+                // never start after the child, but don't trust that enough to
+                // underflow over it.
+                child_start_offset: node.start_byte().saturating_sub(parent.start_byte()),
+                parent_kind: parent.kind(),
+                child_kind: node.kind(),
+            }),
+            None if self.config.root_node_kind.is_some() => out.subtree_roots.push(node.id()),
+            None => {}
+        }
+
+        children
+            .into_iter()
+            .enumerate()
+            .map(|(i, child)| {
+                (
+                    child,
+                    true,
+                    Some((node, node.field_name_for_child(i as u32))),
+                )
+            })
+            .collect()
+    }
+
+    /// Print everything we collected for --pretty-errors. We hold the lock
+    /// on stderr for the whole file, so another thread can't print in the
+    /// middle.
+    fn report_pretty_errors(&mut self) {
+        if self.pretty_error_messages.is_empty() {
+            return;
+        }
+
+        let mut stderr = std::io::stderr().lock();
+        for message in self.pretty_error_messages.drain(..) {
+            // there's nowhere else to report a failure to write to stderr
+            let _ = writeln!(stderr, "{message}");
+        }
+    }
+
+    /// Render an error node like rustc does: the line it starts on, with
+    /// carets underneath the problem.
+    fn pretty_error(&self, node: &Node) -> String {
+        let range = node.range();
+        let len = self.source.as_bytes().len();
+
+        // errors are where we're most likely to see odd ranges, so go through
+        // `Source::bytes` to keep everything in bounds.
+        let before_error = self.source.bytes(0..range.start_byte);
+        let start = before_error.len();
+        let line_start = before_error
+            .iter()
+            .rposition(|byte| *byte == b'\n')
+            .map_or(0, |i| i + 1);
+        let line_end = self
+            .source
+            .bytes(start..len)
+            .iter()
+            .position(|byte| *byte == b'\n')
+            .map_or(len, |i| start + i);
+
+        let before = String::from_utf8_lossy(self.source.bytes(line_start..start));
+        let during =
+            String::from_utf8_lossy(self.source.bytes(start..range.end_byte.min(line_end)));
+        let line = String::from_utf8_lossy(self.source.bytes(line_start..line_end));
+
+        // keep tabs so the carets line up with the source however wide the
+        // terminal draws them.
+        let indent: String = before
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let carets = "^".repeat(during.trim_end_matches('\r').chars().count().max(1));
+
+        let label = if node.is_missing() {
+            format!("missing `{}`", node.kind())
+        } else {
+            "syntax error".to_string()
+        };
+
+        let row = (range.start_point.row + 1).to_string();
+        let gutter = " ".repeat(row.len());
+
+        format!(
+            "error: {label}\n{gutter}--> {}:{}:{}\n{gutter} |\n{row} | {}\n{gutter} | {indent}{carets}",
+            self.path.display(),
+            range.start_point.row + 1,
+            range.start_point.column + 1,
+            line.trim_end_matches('\r'),
+        )
+    }
+
+    /// An indented, human-readable view of the nodes and edges we'll export.
+    fn dump(&self) -> String {
+        let mut children: HashMap<usize, Vec<(usize, Option<&'static str>)>> = HashMap::new();
+        for edge in &self.edges {
+            children
+                .entry(edge.parent)
+                .or_default()
+                .push((edge.child, edge.field));
+        }
+
+        let indexes: HashMap<usize, usize> = self
+            .nodes
+            .iter()
+            .enumerate()
+            .map(|(i, node)| (node.id, i))
+            .collect();
+        let has_parent: HashSet<usize> = self.edges.iter().map(|edge| edge.child).collect();
+
+        let mut todo: Vec<(usize, usize, Option<&'static str>)> = self
+            .nodes
+            .iter()
+            .filter(|node| !has_parent.contains(&node.id))
+            .map(|node| (node.id, 0, None))
+            .rev()
+            .collect();
+
+        // with `--relation-key-mode content`, the same id can show up in more
+        // than one place, so make sure we don't go around in circles.
+        let mut seen = HashSet::with_capacity(self.nodes.len());
+
+        let mut out = String::new();
+        while let Some((id, depth, field)) = todo.pop() {
+            let index = indexes[&id];
+            let node = &self.nodes[index];
+
+            out.push_str(&"  ".repeat(depth));
+            if let Some(field) = field {
+                out.push_str(&format!("{field}: "));
+            }
+            out.push_str(&format!("{} id={}", node.kind, node.id));
+            if !node.is_named {
+                out.push_str(" anonymous");
+            }
+            if node.is_error {
+                out.push_str(" error");
+            }
+            if let Some(location) = self.locations.get(index) {
+                out.push_str(&format!(
+                    " [{}..{}]",
+                    location.start_byte, location.end_byte
+                ));
+            }
+            out.push('\n');
+
+            if !seen.insert(id) {
+                continue;
+            }
+
+            if let Some(kids) = children.get(&id) {
+                for (child, field) in kids.iter().rev() {
+                    todo.push((*child, depth + 1, *field));
+                }
+            }
+        }
+
+        out
+    }
+
+    /// This file's exported nodes as nested JSON.
+    fn tree_json(&self) -> Value {
+        let indexes: HashMap<usize, usize> = self
+            .nodes
+            .iter()
+            .enumerate()
+            .map(|(i, node)| (node.id, i))
+            .collect();
+
+        // edges are in the order we visited children, which is source order
+        let mut children: HashMap<usize, Vec<(usize, Option<&'static str>)>> = HashMap::new();
+        for edge in &self.edges {
+            children
+                .entry(edge.parent)
+                .or_default()
+                .push((indexes[&edge.child], edge.field));
+        }
+
+        let has_parent: HashSet<usize> = self.edges.iter().map(|edge| edge.child).collect();
+
+        let trees: Vec<Value> = self
+            .nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, node)| !has_parent.contains(&node.id))
+            .map(|(i, _)| self.tree_node_json(i, None, &children))
+            .collect();
+
+        json!({
+            "path": self.path,
+            "language": self.language_name,
+            "parse_status": self.parse_status.as_str(),
+            "trees": trees,
+        })
+    }
+
+    fn tree_node_json(
+        &self,
+        index: usize,
+        field: Option<&'static str>,
+        children: &HashMap<usize, Vec<(usize, Option<&'static str>)>>,
+    ) -> Value {
+        let node = &self.nodes[index];
+
+        let mut out = serde_json::Map::new();
+        out.insert("kind".into(), json!(node.kind));
+        out.insert("named".into(), json!(node.is_named));
+        out.insert("is_error".into(), json!(node.is_error));
+        if let Some(field) = field {
+            out.insert("field".into(), json!(field));
+        }
+        if let Some(location) = self.locations.get(index) {
+            out.insert("start_byte".into(), json!(location.start_byte));
+            out.insert("end_byte".into(), json!(location.end_byte));
+            out.insert(
+                "start_point".into(),
+                json!({"row": location.start_row, "column": location.start_column}),
+            );
+            out.insert(
+                "end_point".into(),
+                json!({"row": location.end_row, "column": location.end_column}),
+            );
+        }
+        if let Some(text) = node.source(&self.source) {
+            out.insert(
+                "source".into(),
+                json!(truncate(text, self.config.max_source_len)),
+            );
+        }
+        out.insert(
+            "children".into(),
+            Value::Array(
+                children
+                    .get(&node.id)
+                    .map(|kids| {
+                        kids.iter()
+                            .map(|(child, field)| self.tree_node_json(*child, *field, children))
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+            ),
+        );
+
+        Value::Object(out)
+    }
+
+    /// Replace tree-sitter's node ids (which are pointers, and so change from
+    /// run to run) with each node's position in a pre-order walk of the file.
+    fn renumber(&mut self) {
+        let ids: HashMap<usize, usize> = self
+            .nodes
+            .iter()
+            .enumerate()
+            .map(|(i, node)| (node.id, i))
+            .collect();
+
+        self.remap_ids(&ids);
+    }
+
+    /// After `renumber`, ids are positions in `nodes`, so we can check that
+    /// the edges still make a tree: every edge connects two nodes we
+    /// exported, no node has more than one parent, and parents come before
+    /// their children (which rules out cycles, and means the first node is
+    /// always a root.) There's usually only one root,
+    /// but --root-node-kind and --range can leave more.
+    fn check_renumbered_tree(&self) -> Result<()> {
+        for (i, node) in self.nodes.iter().enumerate() {
+            if node.id != i {
+                bail!("node {i} has id {}", node.id);
+            }
+        }
+
+        let mut parents: HashMap<usize, usize> = HashMap::with_capacity(self.edges.len());
+        for edge in &self.edges {
+            if edge.parent >= self.nodes.len() || edge.child >= self.nodes.len() {
+                bail!(
+                    "edge {} -> {} refers to a node that doesn't exist",
+                    edge.parent,
+                    edge.child
+                );
+            }
+
+            if edge.parent >= edge.child {
+                bail!(
+                    "edge {} -> {} goes from a later node to an earlier one",
+                    edge.parent,
+                    edge.child
+                );
+            }
+
+            if let Some(other) = parents.insert(edge.child, edge.parent) {
+                bail!(
+                    "node {} has two parents ({other} and {})",
+                    edge.child,
+                    edge.parent
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    fn remap_ids(&mut self, ids: &HashMap<usize, usize>) {
+        for node in self.nodes.iter_mut() {
+            node.id = ids[&node.id];
+            for link in [
+                &mut node.first_child,
+                &mut node.last_child,
+                &mut node.next_sibling,
+            ] {
+                if let Some(id) = link {
+                    *id = ids[&*id];
+                }
+            }
+        }
+
+        for location in self.locations.iter_mut() {
+            location.id = ids[&location.id];
+        }
+
+        for edge in self.edges.iter_mut() {
+            edge.parent = ids[&edge.parent];
+            edge.child = ids[&edge.child];
+        }
+
+        for root in self.subtree_roots.iter_mut() {
+            *root = ids[&*root];
+        }
+
+        for error in self.errors.iter_mut() {
+            error.id = ids[&error.id];
+        }
+
+        for (node, next) in self.siblings.iter_mut() {
+            *node = ids[&*node];
+            *next = ids[&*next];
+        }
+
+        for (error, ancestor, _) in self.error_contexts.iter_mut() {
+            *error = ids[&*error];
+            *ancestor = ids[&*ancestor];
+        }
+    }
+
+    /// We walk in pre-order, so each parent's edges are already in source
+    /// order (just with descendants mixed in between.)
+    fn find_siblings(&mut self) {
+        let mut last_child: HashMap<usize, usize> = HashMap::new();
+
+        for edge in &self.edges {
+            if let Some(previous) = last_child.insert(edge.parent, edge.child) {
+                self.siblings.push((previous, edge.child));
+            }
+        }
+    }
+
+    /// Fill in each node's first child, last child, and next sibling for
+    /// --linked-tree. Like `find_siblings`, this relies on each parent's
+    /// edges being in source order.
+    fn link_tree(&mut self) {
+        let indexes: HashMap<usize, usize> = self
+            .nodes
+            .iter()
+            .enumerate()
+            .map(|(i, node)| (node.id, i))
+            .collect();
+
+        let mut last_child: HashMap<usize, usize> = HashMap::new();
+        for edge in &self.edges {
+            let parent = &mut self.nodes[indexes[&edge.parent]];
+            parent.first_child.get_or_insert(edge.child);
+            parent.last_child = Some(edge.child);
+
+            if let Some(previous) = last_child.insert(edge.parent, edge.child) {
+                self.nodes[indexes[&previous]].next_sibling = Some(edge.child);
+            }
+        }
+    }
+
+    /// Go up the exported edges from each error node until we find a named
+    /// node that isn't an error itself. Errors at the top level (with no such
+    /// ancestor) don't get a row.
+    fn find_error_contexts(&mut self) {
+        let nodes: HashMap<usize, &ExportableNode> =
+            self.nodes.iter().map(|node| (node.id, node)).collect();
+
+        let parents: HashMap<usize, usize> = self
+            .edges
+            .iter()
+            .map(|edge| (edge.child, edge.parent))
+            .collect();
+
+        self.error_contexts = self
+            .nodes
+            .iter()
+            .filter(|node| node.is_error)
+            .filter_map(|node| {
+                let mut current = node.id;
+                while let Some(parent) = parents.get(&current) {
+                    let ancestor = nodes.get(parent)?;
+                    if ancestor.is_named && !ancestor.is_error {
+                        return Some((node.id, ancestor.id, ancestor.kind));
+                    }
+                    current = *parent;
+                }
+
+                None
+            })
+            .collect();
+    }
+
+    /// tree-sitter 0.20 doesn't expose `Node::descendant_count`, so we compute
+    /// it ourselves from the exported tree. `nodes` is in pre-order, so going
+    /// backwards means we always see children before their parents.
+    fn count_descendants(&mut self) {
+        let indexes: HashMap<usize, usize> = self
+            .nodes
+            .iter()
+            .enumerate()
+            .map(|(i, node)| (node.id, i))
+            .collect();
+
+        let parents: HashMap<usize, usize> = self
+            .edges
+            .iter()
+            .map(|edge| (edge.child, edge.parent))
+            .collect();
+
+        for i in (0..self.nodes.len()).rev() {
+            let count = self.nodes[i].descendant_count;
+
+            if let Some(parent) = parents
+                .get(&self.nodes[i].id)
+                .and_then(|parent| indexes.get(parent))
+            {
+                self.nodes[*parent].descendant_count += count + 1;
+            }
+        }
+    }
+
+    /// Fill in `start_char` and `end_char` for every location, in one pass
+    /// over the source. We count every byte that doesn't continue a UTF-8
+    /// sequence, so invalid bytes count as one character each.
+    fn count_chars(&mut self) {
+        let mut offsets: Vec<usize> = self
+            .locations
+            .iter()
+            .flat_map(|location| [location.start_byte, location.end_byte])
+            .collect();
+        offsets.sort_unstable();
+        offsets.dedup();
+
+        let bytes = self.source.as_bytes();
+        let mut chars: HashMap<usize, usize> = HashMap::with_capacity(offsets.len());
+        let (mut byte, mut count) = (0, 0);
+        for offset in offsets {
+            // offsets are sorted, so we only need to count from the last one
+            let end = offset.min(bytes.len());
+            count += bytes[byte..end]
+                .iter()
+                .filter(|b| (**b & 0xc0) != 0x80)
+                .count();
+            byte = end;
+            chars.insert(offset, count);
+        }
+
+        for location in self.locations.iter_mut() {
+            location.start_char = chars[&location.start_byte];
+            location.end_char = chars[&location.end_byte];
+        }
+    }
+
+    fn read_source(&mut self) -> Result<()> {
+        let mut file = std::fs::File::open(self.config.resolve(self.path))
+            .wrap_err_with(|| format!("could not open `{}`", self.path.display()))?;
+
+        // mapping an empty file is an error on some platforms, and there's
+        // nothing to save by mapping it anyway.
+        let is_empty = file
+            .metadata()
+            .map(|metadata| metadata.len() == 0)
+            .unwrap_or(false);
+
+        if self.config.decompress {
+            let mut magic = Vec::with_capacity(gzip::MAGIC.len());
+            (&mut file)
+                .take(gzip::MAGIC.len() as u64)
+                .read_to_end(&mut magic)
+                .wrap_err_with(|| format!("could not read `{}`", self.path.display()))?;
+            file.seek(SeekFrom::Start(0))
+                .wrap_err_with(|| format!("could not read `{}`", self.path.display()))?;
+
+            if gzip::is_gzip(&magic) {
+                return self.read_gzipped(file);
+            }
+        }
+
+        self.source = if self.config.mmap && !is_empty {
+            // Safety: this is only unsound if the file is changed while we're
+            // parsing it. tree-sitter copes with arbitrary bytes, and we check
+            // that anything we turn into a string is valid UTF-8.
+            let map = unsafe { memmap2::Mmap::map(&file) }
+                .wrap_err_with(|| format!("could not map `{}`", self.path.display()))?;
+            Source::Mapped(map)
+        } else if self.config.include_bytes {
+            // the whole point of --include-bytes is to handle files that
+            // aren't entirely valid UTF-8, so don't insist that they are.
+            let mut source = Vec::new();
+            file.read_to_end(&mut source).wrap_err_with(|| {
+                format!("could not read source file `{}`", self.path.display())
+            })?;
+            Source::Bytes(source)
+        } else {
+            let mut source = String::new();
+            file.read_to_string(&mut source).wrap_err_with(|| {
+                format!("could not read source file `{}`", self.path.display())
+            })?;
+            Source::Read(source)
+        };
+
+        self.hash = fnv1a(self.source.as_bytes());
 
         Ok(())
     }
 
-    fn read_source(&mut self) -> Result<()> {
-        let mut file = std::fs::File::open(self.path)
-            .wrap_err_with(|| format!("could not open `{}`", self.path.display()))?;
+    fn read_gzipped(&mut self, file: std::fs::File) -> Result<()> {
+        let bytes = gzip::decompress(file)
+            .wrap_err_with(|| format!("could not decompress `{}`", self.path.display()))?;
 
-        file.read_to_string(&mut self.source)
-            .wrap_err_with(|| format!("could not read source file `{}`", self.path.display()))?;
+        self.source = if self.config.include_bytes {
+            Source::Bytes(bytes)
+        } else {
+            Source::Read(String::from_utf8(bytes).wrap_err_with(|| {
+                format!(
+                    "`{}` isn't valid UTF-8 once decompressed",
+                    self.path.display()
+                )
+            })?)
+        };
+        self.hash = fnv1a(self.source.as_bytes());
 
         Ok(())
     }
@@ -458,53 +3832,217 @@ impl<'path> FileExporter<'path> {
 impl From<FileExporter<'_>> for BTreeMap<String, NamedRows> {
     #[instrument(skip(exporter))]
     fn from(exporter: FileExporter<'_>) -> Self {
-        Self::from([
-            (
-                "nodes".into(),
-                NamedRows {
-                    headers: vec![
-                        "path".into(),
-                        "id".into(),
-                        "kind".into(),
-                        "is_error".into(),
-                        "source".into(),
-                    ],
-                    rows: exporter
+        exporter
+            .config
+            .schema()
+            .into_iter()
+            .filter_map(|relation| {
+                let rows: Vec<Vec<Value>> = match relation.name {
+                    "nodes" => exporter
                         .nodes
                         .iter()
-                        .map(|node| node.to_vec(&exporter.source))
+                        .enumerate()
+                        .map(|(i, node)| {
+                            let mut row = node.to_vec(
+                                exporter.config,
+                                exporter.language_name,
+                                &exporter.source,
+                            );
+                            // nodes and locations are pushed together, but
+                            // --no-locations (for one) leaves this empty.
+                            if let Some(location) =
+                                exporter.locations.get(i).filter(|_| exporter.config.wide)
+                            {
+                                row.extend(location.values(exporter.config));
+                            }
+                            row
+                        })
                         .collect(),
-                },
-            ),
-            (
-                "node_locations".into(),
-                NamedRows {
-                    headers: vec![
-                        "path".into(),
-                        "id".into(),
-                        "start_byte".into(),
-                        "start_row".into(),
-                        "start_column".into(),
-                        "end_byte".into(),
-                        "end_row".into(),
-                        "end_column".into(),
-                    ],
-                    rows: exporter.locations.iter().map(|loc| loc.to_vec()).collect(),
-                },
-            ),
-            (
-                "edges".into(),
-                NamedRows {
-                    headers: vec![
-                        "path".into(),
-                        "parent".into(),
-                        "child".into(),
-                        "field".into(),
-                    ],
-                    rows: exporter.edges.iter().map(|edge| edge.to_vec()).collect(),
-                },
-            ),
-        ])
+                    "node_locations" => exporter
+                        .locations
+                        .iter()
+                        .map(|loc| loc.to_vec(exporter.config))
+                        .collect(),
+                    "edges" => exporter
+                        .edges
+                        .iter()
+                        .map(|edge| edge.to_vec(exporter.config, exporter.language_name))
+                        .collect(),
+                    "files" => vec![vec![
+                        json!(exporter.path),
+                        json!(exporter.language_name),
+                        json!(exporter.parse_status.as_str()),
+                        json!(exporter.hash),
+                    ]],
+                    "subtree_roots" => exporter
+                        .subtree_roots
+                        .iter()
+                        .map(|id| vec![json!(exporter.path), json!(id)])
+                        .collect(),
+                    "kind_counts" => exporter
+                        .kind_counts
+                        .iter()
+                        .map(|(kind, count)| {
+                            vec![
+                                json!(exporter.path),
+                                json!(qualified_kind(
+                                    exporter.config,
+                                    exporter.language_name,
+                                    kind
+                                )),
+                                json!(count),
+                            ]
+                        })
+                        .collect(),
+                    "errors" => exporter
+                        .errors
+                        .iter()
+                        .map(|error| {
+                            vec![
+                                json!(exporter.path),
+                                json!(error.id),
+                                json!(error.is_missing),
+                                json!(error.start.row),
+                                json!(error.start.column),
+                            ]
+                        })
+                        .collect(),
+                    "tokens" => {
+                        let mut tokens: Vec<&ExportableNode> =
+                            exporter.nodes.iter().filter(|node| node.is_leaf).collect();
+                        tokens.sort_by_key(|node| node.byte_range);
+
+                        tokens
+                            .into_iter()
+                            .map(|node| {
+                                let (start, end) = node.byte_range;
+                                vec![
+                                    json!(exporter.path),
+                                    json!(node.id),
+                                    json!(exporter.source.get(start..end)),
+                                    json!(start),
+                                    json!(end),
+                                    json!(qualified_kind(
+                                        exporter.config,
+                                        exporter.language_name,
+                                        node.kind
+                                    )),
+                                ]
+                            })
+                            .collect()
+                    }
+                    "sibling_edges" => exporter
+                        .siblings
+                        .iter()
+                        .map(|(node, next)| vec![json!(exporter.path), json!(node), json!(next)])
+                        .collect(),
+                    "error_context" => exporter
+                        .error_contexts
+                        .iter()
+                        .map(|(error, ancestor, kind)| {
+                            vec![
+                                json!(exporter.path),
+                                json!(error),
+                                json!(ancestor),
+                                json!(qualified_kind(
+                                    exporter.config,
+                                    exporter.language_name,
+                                    kind
+                                )),
+                            ]
+                        })
+                        .collect(),
+                    // everything else is about the whole export rather than
+                    // any one file.
+                    _ => return None,
+                };
+
+                Some((
+                    relation.name.to_string(),
+                    NamedRows {
+                        headers: relation.headers(),
+                        rows,
+                    },
+                ))
+            })
+            .collect()
+    }
+}
+
+/// A node kind the way we store it: prefixed with the language if we were
+/// asked to --qualify-kinds.
+fn qualified_kind<'kind>(
+    config: &SourceConfig,
+    language: &str,
+    kind: &'kind str,
+) -> std::borrow::Cow<'kind, str> {
+    if config.qualify_kinds {
+        format!("{language}:{kind}").into()
+    } else {
+        kind.into()
+    }
+}
+
+/// A file's contents, either read into memory or mapped with `--mmap`.
+#[derive(Debug)]
+enum Source {
+    Read(String),
+    Bytes(Vec<u8>),
+    Mapped(memmap2::Mmap),
+}
+
+impl Source {
+    fn as_bytes(&self) -> &[u8] {
+        match self {
+            Self::Read(source) => source.as_bytes(),
+            Self::Bytes(bytes) => bytes,
+            Self::Mapped(map) => &map[..],
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.as_bytes().len()
+    }
+
+    /// Get the bytes in a range, clamped to the source. Nodes should never
+    /// reach past the end of the file, but if a grammar manages it anyway
+    /// (zero-width nodes at EOF are the usual suspects), we'd rather hash a
+    /// short or empty slice than panic.
+    fn bytes(&self, range: std::ops::Range<usize>) -> &[u8] {
+        let bytes = self.as_bytes();
+        let end = range.end.min(bytes.len());
+        let start = range.start.min(end);
+
+        &bytes[start..end]
+    }
+
+    /// Get the text in a byte range, if it's in bounds and valid UTF-8.
+    fn get(&self, range: std::ops::Range<usize>) -> Option<&str> {
+        match self {
+            Self::Read(source) => source.get(range),
+            Self::Bytes(bytes) => bytes
+                .get(range)
+                .and_then(|bytes| std::str::from_utf8(bytes).ok()),
+            Self::Mapped(map) => map
+                .get(range)
+                .and_then(|bytes| std::str::from_utf8(bytes).ok()),
+        }
+    }
+}
+
+/// How parsing a file went, as stored in the `files` relation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ParseStatus {
+    Ok,
+    HasErrors,
+}
+
+impl ParseStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Ok => "ok",
+            Self::HasErrors => "has_errors",
+        }
     }
 }
 
@@ -513,8 +4051,16 @@ struct ExportableNode<'path> {
     path: &'path Path,
     id: usize,
     kind: &'static str,
+    is_named: bool,
     is_error: bool,
+    is_leaf: bool,
     source_bytes: Option<(usize, usize)>,
+    byte_range: (usize, usize),
+    descendant_count: usize,
+    source_hash: i64,
+    first_child: Option<usize>,
+    last_child: Option<usize>,
+    next_sibling: Option<usize>,
 }
 
 impl<'path> ExportableNode<'path> {
@@ -530,22 +4076,180 @@ impl<'path> ExportableNode<'path> {
             path,
             id: node.id(),
             kind: node.kind(),
+            is_named: node.is_named(),
             is_error: node.is_error(),
+            is_leaf: node.child_count() == 0,
             source_bytes,
+            byte_range: (range.start_byte, range.end_byte),
+            // filled in by `FileExporter::count_descendants` once we've seen
+            // the whole tree.
+            descendant_count: 0,
+            // filled in by `FileExporter::slurp` if we're asked for it.
+            source_hash: 0,
+            // filled in by `FileExporter::link_tree` for --linked-tree.
+            first_child: None,
+            last_child: None,
+            next_sibling: None,
         }
     }
 
-    fn to_vec(&self, source: &str) -> Vec<Value> {
-        vec![
+    fn to_vec(&self, config: &SourceConfig, language: &str, source: &Source) -> Vec<Value> {
+        let mut row = vec![
             json!(self.path),
             json!(self.id),
-            json!(self.kind),
+            json!(qualified_kind(config, language, self.kind)),
             json!(self.is_error),
             json!(self
+                .source(source)
+                .map(|text| truncate(text, config.max_source_len))),
+            json!(self.descendant_count),
+        ];
+
+        if config.source_hash {
+            row.push(json!(self.source_hash));
+        }
+
+        if config.normalize_source {
+            row.push(json!(self.source(source).map(|text| {
+                let normalized = text.split_whitespace().collect::<Vec<&str>>().join(" ");
+                truncate(&normalized, config.max_source_len).into_owned()
+            })));
+        }
+
+        if config.node_uids {
+            row.push(json!(node_uid(self.path, self.id)));
+        }
+
+        if config.token_column {
+            let (start, end) = self.byte_range;
+            row.push(json!(if self.is_named {
+                None
+            } else {
+                source.get(start..end)
+            }));
+        }
+
+        if config.include_bytes {
+            row.push(json!(self
                 .source_bytes
-                .and_then(|(start, end)| source.get(start..end))),
-        ]
+                .and_then(|(start, end)| source.as_bytes().get(start..end))
+                .map(base64::encode)));
+        }
+
+        if config.linked_tree {
+            row.extend([
+                json!(self.first_child),
+                json!(self.last_child),
+                json!(self.next_sibling),
+            ]);
+        }
+
+        row
+    }
+
+    /// Get the source text for this node. tree-sitter's byte offsets are
+    /// exclusive at the end, so a leaf that runs right up to EOF (no trailing
+    /// newline) has `end == source.len()`, which `get` handles fine. CRLF line
+    /// endings don't need special care either: the `\r` is just another byte
+    /// and never ends up inside a leaf's range.
+    fn source<'source>(&self, source: &'source Source) -> Option<&'source str> {
+        let (start, end) = self.source_bytes?;
+
+        match source.get(start..end) {
+            Some(text) => Some(text),
+            None => {
+                tracing::warn!(
+                    path = ?self.path,
+                    start,
+                    end,
+                    len = source.len(),
+                    "node's byte range is out of bounds or not valid UTF-8",
+                );
+                None
+            }
+        }
+    }
+}
+
+/// The first error or missing node under `node`, in source order.
+fn first_error(node: Node) -> Option<Node> {
+    if node.is_error() || node.is_missing() {
+        return Some(node);
+    }
+
+    let mut cursor = node.walk();
+    let children: Vec<Node> = node.children(&mut cursor).collect();
+    children
+        .into_iter()
+        .filter(|child| child.has_error())
+        .find_map(first_error)
+}
+
+/// How many error and missing nodes are under `node` (including itself.) We
+/// only look inside subtrees that have errors, so clean code costs nothing.
+fn count_errors(node: Node) -> usize {
+    let mut cursor = node.walk();
+    let children: Vec<Node> = node.children(&mut cursor).collect();
+
+    usize::from(node.is_error() || node.is_missing())
+        + children
+            .into_iter()
+            .filter(|child| child.has_error())
+            .map(count_errors)
+            .sum::<usize>()
+}
+
+/// Cut `text` down to at most `max_len` bytes (plus a marker), without
+/// splitting a character.
+fn truncate(text: &str, max_len: Option<usize>) -> std::borrow::Cow<'_, str> {
+    match max_len {
+        Some(max_len) if text.len() > max_len => {
+            let mut end = max_len;
+            while !text.is_char_boundary(end) {
+                end -= 1;
+            }
+
+            std::borrow::Cow::Owned(format!("{}…", &text[..end]))
+        }
+        _ => std::borrow::Cow::Borrowed(text),
+    }
+}
+
+/// 64-bit FNV-1a. We don't use `std`'s hasher because its output isn't
+/// guaranteed to be the same from one release to the next, and these hashes
+/// get stored.
+pub fn fnv1a(bytes: &[u8]) -> i64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x100000001b3);
     }
+
+    hash as i64
+}
+
+/// An id for a node based on its kind and source text, so the same code
+/// gets the same id wherever it is. We drop the top bit so these fit in
+/// Cozo's (signed) `Int`.
+fn content_id(kind: &str, source: &[u8]) -> usize {
+    let mut bytes = Vec::with_capacity(kind.len() + 1 + source.len());
+    bytes.extend_from_slice(kind.as_bytes());
+    bytes.push(0);
+    bytes.extend_from_slice(source);
+
+    (fnv1a(&bytes) as u64 >> 1) as usize
+}
+
+/// An id for a node that's unique across the whole export instead of just
+/// within its file. These are hashes, so they could collide in theory, but
+/// it would take billions of nodes for that to be likely.
+fn node_uid(path: &Path, id: usize) -> i64 {
+    let mut bytes = path.to_string_lossy().into_owned().into_bytes();
+    bytes.push(b'#');
+    bytes.extend_from_slice(id.to_string().as_bytes());
+
+    fnv1a(&bytes)
 }
 
 #[derive(Debug)]
@@ -558,6 +4262,8 @@ struct ExportableNodeLocation<'path> {
     end_byte: usize,
     end_row: usize,
     end_column: usize,
+    start_char: usize,
+    end_char: usize,
 }
 
 impl<'path> ExportableNodeLocation<'path> {
@@ -573,20 +4279,41 @@ impl<'path> ExportableNodeLocation<'path> {
             end_byte: range.end_byte,
             end_row: range.end_point.row,
             end_column: range.end_point.column,
+            // filled in by `FileExporter::count_chars` for --char-offsets.
+            start_char: 0,
+            end_char: 0,
         }
     }
 
-    fn to_vec(&self) -> Vec<Value> {
-        vec![
-            json!(self.path),
-            json!(self.id),
-            json!(self.start_byte),
-            json!(self.start_row),
-            json!(self.start_column),
-            json!(self.end_byte),
-            json!(self.end_row),
-            json!(self.end_column),
-        ]
+    fn to_vec(&self, config: &SourceConfig) -> Vec<Value> {
+        let mut row = vec![json!(self.path), json!(self.id)];
+        row.extend(self.values(config));
+        row
+    }
+
+    /// Just the location columns, for when they're inlined into `nodes`.
+    fn values(&self, config: &SourceConfig) -> Vec<Value> {
+        let mut values = match config.location_format {
+            LocationFormat::Columns => vec![
+                json!(self.start_byte),
+                json!(self.start_row),
+                json!(self.start_column),
+                json!(self.end_byte),
+                json!(self.end_row),
+                json!(self.end_column),
+            ],
+            LocationFormat::Packed => vec![json!(format!(
+                "{}:{}-{}:{}",
+                self.start_row, self.start_column, self.end_row, self.end_column
+            ))],
+        };
+
+        if config.char_offsets {
+            values.push(json!(self.start_char));
+            values.push(json!(self.end_char));
+        }
+
+        values
     }
 }
 
@@ -596,15 +4323,616 @@ struct ExportableEdge<'path> {
     parent: usize,
     child: usize,
     field: Option<&'static str>,
+    field_id: Option<u16>,
+    child_start_offset: usize,
+    parent_kind: &'static str,
+    child_kind: &'static str,
 }
 
 impl ExportableEdge<'_> {
-    fn to_vec(&self) -> Vec<Value> {
-        vec![
+    fn to_vec(&self, config: &SourceConfig, language: &str) -> Vec<Value> {
+        let mut row = vec![
             json!(self.path),
             json!(self.parent),
             json!(self.child),
             json!(self.field),
-        ]
+        ];
+
+        if config.edge_offsets {
+            row.push(json!(self.child_start_offset));
+        }
+
+        if config.field_ids {
+            row.push(json!(self.field_id));
+        }
+
+        if config.has_field {
+            row.push(json!(self.field.is_some()));
+        }
+
+        if config.denormalize_edges {
+            row.push(json!(qualified_kind(config, language, self.parent_kind)));
+            row.push(json!(qualified_kind(config, language, self.child_kind)));
+        }
+
+        if config.node_uids {
+            row.push(json!(node_uid(self.path, self.parent)));
+            row.push(json!(node_uid(self.path, self.child)));
+        }
+
+        row
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser as _;
+
+    #[derive(clap::Parser)]
+    struct Args {
+        #[command(flatten)]
+        source: SourceConfig,
+    }
+
+    fn config(args: &[&str]) -> SourceConfig {
+        Args::try_parse_from(std::iter::once("tree-db").chain(args.iter().copied()))
+            .expect("test arguments should parse")
+            .source
+    }
+
+    fn exporter_config(args: &[&str]) -> ExporterConfig {
+        ExporterConfig::try_parse_from(std::iter::once("tree-db").chain(args.iter().copied()))
+            .expect("test arguments should parse")
+    }
+
+    /// Parse `source` as if it were the contents of `test.json`.
+    fn slurp<'config>(config: &'config SourceConfig, source: &str) -> FileExporter<'config> {
+        let mut exporter = FileExporter::new(
+            config,
+            "json",
+            tree_sitter_json::language(),
+            Path::new("test.json"),
+        );
+        exporter.source = Source::Read(source.to_string());
+        exporter.hash = fnv1a(exporter.source.as_bytes());
+        exporter.slurp().expect("test source should export");
+
+        exporter
+    }
+
+    /// Export `source` as if it were the contents of `test.json`.
+    fn export(config: &SourceConfig, source: &str) -> BTreeMap<String, NamedRows> {
+        slurp(config, source).into()
+    }
+
+    /// Every row in `relation`, as a map from column name to value.
+    fn rows(
+        relations: &BTreeMap<String, NamedRows>,
+        relation: &str,
+    ) -> Vec<BTreeMap<String, Value>> {
+        let data = &relations[relation];
+        data.rows
+            .iter()
+            .map(|row| {
+                data.headers
+                    .iter()
+                    .cloned()
+                    .zip(row.iter().cloned())
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn blank_files_export_a_clean_document() {
+        for source in ["", " \n\t\r\n"] {
+            let config = config(&["--on-error", "fail"]);
+            let relations = export(&config, source);
+
+            let files = rows(&relations, "files");
+            assert_eq!(files.len(), 1);
+            assert_eq!(files[0]["parse_status"], json!("ok"));
+
+            let nodes = rows(&relations, "nodes");
+            assert_eq!(nodes.len(), 1, "{source:?} should only have a root");
+            assert_eq!(nodes[0]["kind"], json!("document"));
+            assert!(rows(&relations, "edges").is_empty());
+        }
+    }
+
+    #[test]
+    fn edges_connect_distinct_nodes() {
+        for args in [&[][..], &["--named-only"][..]] {
+            let config = config(args);
+            let exporter = slurp(&config, r#"{"a": [1, 2]}"#);
+
+            let ids: HashSet<usize> = exporter.nodes.iter().map(|node| node.id).collect();
+            assert_eq!(exporter.edges.len(), ids.len() - 1, "{args:?}");
+
+            let mut children = HashSet::new();
+            for edge in &exporter.edges {
+                assert_ne!(edge.parent, edge.child, "{args:?}");
+                assert!(ids.contains(&edge.parent), "{args:?}");
+                assert!(ids.contains(&edge.child), "{args:?}");
+                assert!(children.insert(edge.child), "{args:?}");
+            }
+        }
+    }
+
+    /// `(source, start_byte, end_byte)` for each node that has `source`,
+    /// in source order.
+    fn leaves(relations: &BTreeMap<String, NamedRows>) -> Vec<(Value, Value, Value)> {
+        let locations: HashMap<u64, BTreeMap<String, Value>> = rows(relations, "node_locations")
+            .into_iter()
+            .map(|location| (location["id"].as_u64().unwrap(), location))
+            .collect();
+
+        let mut leaves: Vec<(Value, Value, Value)> = rows(relations, "nodes")
+            .into_iter()
+            .filter(|node| !node["source"].is_null())
+            .map(|node| {
+                let location = &locations[&node["id"].as_u64().unwrap()];
+                (
+                    node["source"].clone(),
+                    location["start_byte"].clone(),
+                    location["end_byte"].clone(),
+                )
+            })
+            .collect();
+        leaves.sort_by_key(|(_, start, _)| start.as_u64());
+        leaves
+    }
+
+    #[test]
+    fn crlf_line_endings_stay_out_of_leaves() {
+        let relations = export(&config(&[]), "[1,\r\n22]\r\n");
+
+        assert_eq!(
+            leaves(&relations),
+            vec![
+                (json!("1"), json!(1), json!(2)),
+                (json!("22"), json!(5), json!(7)),
+            ]
+        );
+    }
+
+    #[test]
+    fn leaves_can_end_at_eof() {
+        let relations = export(&config(&[]), "[1,\n22]");
+        assert_eq!(
+            leaves(&relations),
+            vec![
+                (json!("1"), json!(1), json!(2)),
+                (json!("22"), json!(4), json!(6)),
+            ]
+        );
+
+        // with nothing after it at all
+        let relations = export(&config(&[]), "333");
+        assert_eq!(leaves(&relations), vec![(json!("333"), json!(0), json!(3))]);
+    }
+
+    #[test]
+    fn overlapping_arguments_find_each_file_once() {
+        let dir = tempfile::Builder::new()
+            .prefix("tree-db")
+            .tempdir()
+            .unwrap();
+        std::fs::write(dir.path().join("a.json"), "[]").unwrap();
+        std::fs::write(dir.path().join("b.json"), "{}").unwrap();
+
+        let dir_arg = dir.path().display().to_string();
+        let file_arg = dir.path().join("a.json").display().to_string();
+        let dotted_arg = dir.path().join(".").join("a.json").display().to_string();
+
+        let config = config(&["--sort-files", &dir_arg, &file_arg, &dotted_arg]);
+        let found = config.files(&RunContext::default()).unwrap();
+
+        let names: Vec<&std::ffi::OsStr> = found
+            .paths
+            .iter()
+            .filter_map(|file| file.path.file_name())
+            .collect();
+        assert_eq!(names, ["a.json", "b.json"]);
+    }
+
+    #[test]
+    fn walking_reads_shebangs_only_for_extensionless_files() {
+        let dir = tempdir();
+        std::fs::write(dir.path().join("a.json"), "[]").unwrap();
+        std::fs::write(dir.path().join("build"), "#!/usr/bin/env python3\n").unwrap();
+        std::fs::write(dir.path().join("notes.unknown"), "#!/bin/sh\n").unwrap();
+
+        let dir_arg = dir.path().display().to_string();
+        for (args, expected) in [
+            (&["--sort-files"][..], &["a.json", "build"][..]),
+            (&["--sort-files", "--no-shebang"][..], &["a.json"][..]),
+        ] {
+            let mut args = args.to_vec();
+            args.push(&dir_arg);
+            let found = config(&args).files(&RunContext::default()).unwrap();
+
+            let names: Vec<&std::ffi::OsStr> = found
+                .paths
+                .iter()
+                .filter_map(|file| file.path.file_name())
+                .collect();
+            assert_eq!(names, expected, "{args:?}");
+        }
+    }
+
+    #[test]
+    fn cache_ignores_are_real_options() {
+        let options = serde_json::to_value(config(&[])).unwrap();
+        for ignored in CACHE_IGNORES {
+            assert!(options.get(ignored).is_some(), "{ignored} is not an option");
+        }
+    }
+
+    #[test]
+    fn cache_fingerprint_covers_everything_but_the_ignores() {
+        let base = config(&[]).cache_fingerprint().unwrap();
+
+        for args in [
+            &["--language-alias", "mjs=javascript"][..],
+            &["--grammar-manifest", "grammars.json"][..],
+            &["--grammar-search-recursive"][..],
+            &["--named-only"][..],
+        ] {
+            assert_ne!(config(args).cache_fingerprint().unwrap(), base, "{args:?}");
+        }
+
+        for args in [&["--sort-files"][..], &["--timing"][..], &["src"][..]] {
+            assert_eq!(config(args).cache_fingerprint().unwrap(), base, "{args:?}");
+        }
+    }
+
+    fn tempdir() -> tempfile::TempDir {
+        tempfile::Builder::new()
+            .prefix("tree-db")
+            .tempdir()
+            .unwrap()
+    }
+
+    #[test]
+    fn output_can_be_repeated() {
+        let cfg = exporter_config(&[
+            "--output",
+            "cozo-json",
+            "--output",
+            "cozo-sqlite",
+            "-o",
+            "tree.sqlite",
+        ]);
+        assert_eq!(cfg.output, vec![Output::CozoJson, Output::CozoSqlite]);
+    }
+
+    /// Export `source` as `test.json` and write it to `out` as `output`
+    /// would for a whole database (so with every relation.)
+    fn write_output(out: &Path, output: &str, args: &[&str], source: &str) {
+        let out_arg = out.display().to_string();
+
+        let mut full_args = vec!["--output", output, "--output-path", &out_arg];
+        full_args.extend_from_slice(args);
+        let cfg = exporter_config(&full_args);
+
+        let mut relations = export(&cfg.source, source);
+        relations.insert(
+            "languages".into(),
+            NamedRows {
+                headers: cfg.source.relation("languages").unwrap().headers(),
+                rows: Vec::new(),
+            },
+        );
+
+        cfg.output[0]
+            .writer(&cfg)
+            .unwrap()
+            .write_relations(&relations, &cfg)
+            .unwrap();
+    }
+
+    #[test]
+    fn postgres_edges_can_be_missing_fields() {
+        let dir = tempdir();
+        let out = dir.path().join("out.sql");
+        // the number in an array isn't in a field
+        write_output(&out, "postgres-copy", &[], "[1]");
+        let script = std::fs::read_to_string(out).unwrap();
+
+        let create_edges = script
+            .split(';')
+            .find(|statement| statement.contains("CREATE TABLE edges"))
+            .unwrap();
+        assert!(!create_edges.contains("PRIMARY KEY"), "{create_edges}");
+        assert!(script.contains("CREATE INDEX edges_keys ON edges"));
+
+        let copied_edges: Vec<&str> = script
+            .split("COPY edges")
+            .nth(1)
+            .unwrap()
+            .lines()
+            .skip(1)
+            .take_while(|line| *line != "\\.")
+            .collect();
+        assert!(
+            copied_edges
+                .iter()
+                .any(|line| line.split('\t').any(|cell| cell == "\\N")),
+            "{copied_edges:?}"
+        );
+    }
+
+    /// The cells in `column` of a CSV file.
+    fn csv_column(path: &Path, column: &str) -> Vec<String> {
+        let mut reader = csv::Reader::from_path(path).unwrap();
+        let index = reader
+            .headers()
+            .unwrap()
+            .iter()
+            .position(|header| header == column)
+            .unwrap();
+
+        reader
+            .records()
+            .map(|record| record.unwrap()[index].to_string())
+            .collect()
+    }
+
+    #[test]
+    fn csv_null_fills_missing_values() {
+        let dir = tempdir();
+        // the object has no source (it has children) and isn't in a field,
+        // but the pair's key and value are both in fields.
+        write_output(dir.path(), "csv", &["--csv-null", "NULL"], r#"{"a": 1}"#);
+
+        let sources = csv_column(&dir.path().join("nodes.csv"), "source");
+        assert!(sources.contains(&"NULL".to_string()), "{sources:?}");
+        assert!(sources.contains(&"1".to_string()), "{sources:?}");
+        assert!(!sources.contains(&String::new()), "{sources:?}");
+
+        let fields = csv_column(&dir.path().join("edges.csv"), "field");
+        assert!(fields.contains(&"NULL".to_string()), "{fields:?}");
+        assert!(fields.contains(&"value".to_string()), "{fields:?}");
+        assert!(!fields.contains(&String::new()), "{fields:?}");
+    }
+
+    #[test]
+    fn files_outside_the_root_resolve_to_themselves() {
+        let dir = tempdir();
+        std::fs::create_dir(dir.path().join("repo")).unwrap();
+        std::fs::write(dir.path().join("repo").join("in.json"), "[]").unwrap();
+        std::fs::write(dir.path().join("out.json"), "{}").unwrap();
+
+        let root_arg = dir.path().join("repo").display().to_string();
+        for extra in [&[][..], &["--no-normalize-paths"][..]] {
+            let mut args = vec![
+                "--sort-files",
+                "--root",
+                &root_arg,
+                "in.json",
+                "../out.json",
+            ];
+            args.extend_from_slice(extra);
+            let config = config(&args);
+            let found = config.files(&RunContext::default()).unwrap();
+
+            let paths: Vec<&Path> = found.paths.iter().map(|file| file.path.as_path()).collect();
+            let outside = dir.path().join("out.json").canonicalize().unwrap();
+            assert!(paths.contains(&Path::new("in.json")), "{paths:?}");
+            assert!(paths.contains(&outside.as_path()), "{paths:?}");
+
+            for path in paths {
+                assert!(config.resolve(path).is_file(), "{path:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn error_counts_include_missing_and_unexported_nodes() {
+        // the `]` is missing, and it's anonymous, so --named-only leaves it
+        // out of the rows.
+        let config = config(&["--named-only"]);
+        let exporter = slurp(&config, "[1");
+
+        let exported = exporter.nodes.iter().filter(|node| node.is_error).count();
+        assert!(
+            exporter.error_nodes > exported,
+            "counted {} errors, but only {exported} are in the rows",
+            exporter.error_nodes
+        );
+    }
+
+    #[test]
+    fn chunk_size_splits_imports_by_relation() {
+        let source = format!("[{}1]", "1,".repeat(10));
+        let relations = export(&config(&[]), &source);
+
+        let batches = config(&[]).import_batches(relations.clone());
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].len(), relations.len());
+
+        let batches = config(&["--chunk-size", "4"]).import_batches(relations.clone());
+        let mut expected = 0;
+        for (name, data) in &relations {
+            let sizes: Vec<usize> = batches
+                .iter()
+                .filter_map(|batch| batch.get(name))
+                .map(|chunk| chunk.rows.len())
+                .collect();
+
+            assert_eq!(sizes.iter().sum::<usize>(), data.rows.len(), "{name}");
+            assert!(
+                sizes.iter().all(|size| (1..=4).contains(size)),
+                "{name}: {sizes:?}"
+            );
+            assert_eq!(
+                sizes.len(),
+                data.rows.len().div_ceil(4),
+                "{name}: {sizes:?}"
+            );
+            expected += sizes.len();
+        }
+
+        assert!(batches.iter().all(|batch| batch.len() == 1));
+        assert_eq!(batches.len(), expected);
+    }
+
+    #[test]
+    fn has_field_matches_field() {
+        let relations = export(&config(&["--has-field"]), r#"{"a": 1}"#);
+
+        let kinds: HashMap<u64, Value> = rows(&relations, "nodes")
+            .into_iter()
+            .map(|node| (node["id"].as_u64().unwrap(), node["kind"].clone()))
+            .collect();
+        let kind = |id: &Value| kinds[&id.as_u64().unwrap()].clone();
+
+        // a pair has its key and value in fields, but not the `:` between
+        let mut pair_children: Vec<(Value, Value, Value)> = rows(&relations, "edges")
+            .into_iter()
+            .filter(|edge| kind(&edge["parent"]) == json!("pair"))
+            .map(|edge| {
+                (
+                    kind(&edge["child"]),
+                    edge["field"].clone(),
+                    edge["has_field"].clone(),
+                )
+            })
+            .collect();
+        pair_children.sort_by_key(|(kind, _, _)| kind.to_string());
+
+        assert_eq!(
+            pair_children,
+            vec![
+                (json!(":"), Value::Null, json!(false)),
+                (json!("number"), json!("value"), json!(true)),
+                (json!("string"), json!("key"), json!(true)),
+            ]
+        );
+    }
+
+    #[test]
+    fn stable_ids_keep_the_tree_intact() {
+        let config = config(&["--stable-ids"]);
+        let exporter = slurp(&config, r#"{"a": [1, 2], "b": {"c": null}}"#);
+        exporter.check_renumbered_tree().unwrap();
+
+        let ids: Vec<usize> = exporter.nodes.iter().map(|node| node.id).collect();
+        assert_eq!(ids, (0..exporter.nodes.len()).collect::<Vec<usize>>());
+
+        let mut parents: HashMap<usize, Vec<usize>> = HashMap::new();
+        for edge in &exporter.edges {
+            parents.entry(edge.child).or_default().push(edge.parent);
+        }
+
+        assert!(!parents.contains_key(&0), "the root has a parent");
+        for id in 1..exporter.nodes.len() {
+            assert_eq!(
+                parents.get(&id).map(Vec::len),
+                Some(1),
+                "node {id} should have exactly one parent"
+            );
+        }
+    }
+
+    #[test]
+    fn broken_trees_fail_the_renumbering_check() {
+        let config = config(&["--stable-ids"]);
+
+        let mut exporter = slurp(&config, "[1, 2]");
+        let last = exporter.edges.last().unwrap();
+        let (path, child) = (last.path, last.child);
+        // give the last node a second parent
+        exporter.edges.push(ExportableEdge {
+            path,
+            parent: 0,
+            child,
+            field: None,
+            field_id: None,
+            child_start_offset: 0,
+            parent_kind: "document",
+            child_kind: "number",
+        });
+        assert!(exporter.check_renumbered_tree().is_err());
+
+        let mut exporter = slurp(&config, "[1, 2]");
+        exporter.nodes.swap(0, 1);
+        assert!(exporter.check_renumbered_tree().is_err());
+    }
+
+    #[cfg(feature = "duckdb")]
+    #[test]
+    fn duckdb_output_can_be_queried() {
+        let dir = tempdir();
+        let out = dir.path().join("out.duckdb");
+        write_output(&out, "duckdb", &[], "[1, 2]");
+
+        let conn = duckdb::Connection::open(&out).unwrap();
+        let count = |query: &str| -> i64 { conn.query_row(query, [], |row| row.get(0)).unwrap() };
+
+        // document, array, `[`, two numbers, `,`, and `]`
+        assert_eq!(count("SELECT count(*) FROM nodes"), 7);
+        assert_eq!(
+            count("SELECT count(*) FROM nodes WHERE kind = 'number' AND source IS NOT NULL"),
+            2
+        );
+        assert_eq!(count("SELECT count(*) FROM edges WHERE field IS NULL"), 6);
+    }
+
+    #[test]
+    fn zero_width_source_at_eof_is_empty() {
+        let text = "[1";
+        let mut parser = Parser::new();
+        parser.set_language(tree_sitter_json::language()).unwrap();
+        let tree = parser.parse(text, None).unwrap();
+
+        // the parser fills in the missing `]` as a zero-width node right at
+        // the end of the file. It's anonymous, so it wouldn't get a source
+        // when exported; we give it one to see what `source` does with it.
+        let array = tree.root_node().child(0).unwrap();
+        let closing = array.child(array.child_count() - 1).unwrap();
+        assert!(closing.is_missing());
+        assert_eq!(closing.byte_range(), text.len()..text.len());
+
+        let mut node = ExportableNode::from(Path::new("test.json"), &closing);
+        node.source_bytes = Some((text.len(), text.len()));
+
+        let dir = tempdir();
+        let mapped_path = dir.path().join("test.json");
+        std::fs::write(&mapped_path, text).unwrap();
+        let mapped = unsafe { memmap2::Mmap::map(&std::fs::File::open(&mapped_path).unwrap()) };
+
+        for source in [
+            Source::Read(text.to_string()),
+            Source::Bytes(text.as_bytes().to_vec()),
+            Source::Mapped(mapped.unwrap()),
+        ] {
+            assert_eq!(node.source(&source), Some(""), "{source:?}");
+        }
+    }
+
+    #[test]
+    fn pretty_errors_can_point_at_eof() {
+        let mut parser = Parser::new();
+        parser.set_language(tree_sitter_json::language()).unwrap();
+        let tree = parser.parse("[1", None).unwrap();
+        let error = first_error(tree.root_node()).unwrap();
+
+        let config = config(&[]);
+        let message = slurp(&config, "[1").pretty_error(&error);
+        assert!(message.contains("test.json:1:"), "{message}");
+        assert!(message.contains("1 | [1"), "{message}");
+    }
+
+    #[test]
+    fn blank_files_record_no_errors() {
+        for source in ["", "   ", "\n\n"] {
+            let config = config(&["--on-error", "record"]);
+            let relations = export(&config, source);
+
+            assert!(rows(&relations, "errors").is_empty(), "{source:?}");
+        }
     }
 }
@@ -0,0 +1,128 @@
+use color_eyre::eyre::{Result, WrapErr};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use std::path::{Component, Path, PathBuf};
+
+/// The positional `file` arguments, with any globs expanded into a directory
+/// to walk plus a pattern to filter by. Shells usually expand globs for us,
+/// but not on Windows or when the pattern is quoted (like `"src/**/*.rs"`.)
+#[derive(Debug)]
+pub struct FileArgs {
+    /// Where to start walking.
+    pub roots: Vec<PathBuf>,
+
+    /// Arguments that were plain paths. Anything under these is included.
+    literal: Vec<PathBuf>,
+
+    /// Patterns from arguments that were globs. Anything under one of their
+    /// roots has to match one of these.
+    globs: GlobSet,
+}
+
+impl FileArgs {
+    pub fn new(args: &[PathBuf]) -> Result<Self> {
+        let mut roots = Vec::with_capacity(args.len());
+        let mut literal = Vec::with_capacity(args.len());
+        let mut globs = GlobSetBuilder::new();
+
+        for arg in args {
+            let pattern = arg.to_string_lossy();
+            if !is_glob(&pattern) {
+                roots.push(arg.clone());
+                literal.push(arg.clone());
+                continue;
+            }
+
+            roots.push(glob_root(arg));
+            globs.add(
+                GlobBuilder::new(
+                    strip_current_dir(Path::new(pattern.as_ref()))
+                        .to_string_lossy()
+                        .as_ref(),
+                )
+                // match like a shell would, so `*.rs` doesn't match
+                // `src/main.rs`.
+                .literal_separator(true)
+                .build()
+                .wrap_err_with(|| format!("could not parse `{pattern}` as a glob"))?,
+            );
+        }
+
+        Ok(Self {
+            roots,
+            literal,
+            globs: globs
+                .build()
+                .wrap_err("could not build file glob matcher")?,
+        })
+    }
+
+    /// Are all the arguments plain paths to existing files (so there's
+    /// nothing to walk)?
+    pub fn all_files(&self) -> bool {
+        self.globs.is_empty() && self.literal.iter().all(|path| path.is_file())
+    }
+
+    /// Should we include this path, which we found by walking `roots`?
+    pub fn includes(&self, path: &Path) -> bool {
+        self.globs.is_empty()
+            || self.literal.iter().any(|root| path.starts_with(root))
+            || self.globs.is_match(strip_current_dir(path))
+    }
+}
+
+fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?', '[', '{'])
+}
+
+/// The longest leading part of the glob that doesn't contain any
+/// metacharacters, which is where we need to start walking.
+fn glob_root(pattern: &Path) -> PathBuf {
+    let root: PathBuf = pattern
+        .components()
+        .take_while(|component| !is_glob(&component.as_os_str().to_string_lossy()))
+        .collect();
+
+    if root.as_os_str().is_empty() {
+        PathBuf::from(".")
+    } else {
+        root
+    }
+}
+
+/// The walker gives us paths like `./src/main.rs` when we start from `.`,
+/// but people write globs like `src/*.rs`.
+fn strip_current_dir(path: &Path) -> &Path {
+    match path.components().next() {
+        Some(Component::CurDir) => path.strip_prefix(".").unwrap_or(path),
+        _ => path,
+    }
+}
+
+/// Clean up `.` and `..` components without touching the filesystem, so
+/// `./src/main.rs` and `src/../src/main.rs` both become `src/main.rs`. We use
+/// these as relation keys, so the same file should always look the same.
+pub fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::with_capacity(path.as_os_str().len());
+
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match out.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    out.pop();
+                }
+                // we can't go above the root, and if we're already a chain
+                // of `..`s we have to keep them.
+                Some(Component::RootDir) | Some(Component::Prefix(_)) => {}
+                _ => out.push(".."),
+            },
+            other => out.push(other),
+        }
+    }
+
+    if out.as_os_str().is_empty() {
+        PathBuf::from(".")
+    } else {
+        out
+    }
+}
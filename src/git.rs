@@ -0,0 +1,69 @@
+use color_eyre::eyre::{bail, Result, WrapErr};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The files git says are staged, modified (compared to `HEAD`), or new and
+/// not ignored, in the repo containing `dir`. These are canonical paths so
+/// they can be compared with whatever we find while walking. Returns `None`
+/// if `dir` isn't in a git repo (or we can't run git.)
+pub fn changed_files(dir: &Path) -> Result<Option<HashSet<PathBuf>>> {
+    let toplevel = match git(dir, &["rev-parse", "--show-toplevel"]) {
+        Ok(toplevel) => PathBuf::from(toplevel.trim_end()),
+        Err(err) => {
+            tracing::debug!(?err, "could not find the top of the git repo");
+            return Ok(None);
+        }
+    };
+
+    // a repo with no commits yet doesn't have a `HEAD` to diff against, but
+    // anything in it must be staged.
+    let names = match git(dir, &["diff", "--name-only", "--diff-filter=d", "HEAD"]) {
+        Ok(names) => names,
+        Err(_) => git(dir, &["diff", "--name-only", "--diff-filter=d", "--cached"])
+            .wrap_err("could not get staged files")?,
+    };
+
+    // brand-new files don't show up in `diff` until they're staged. Like
+    // `diff`, `--full-name` gives us paths from the top of the repo.
+    let untracked = git(
+        dir,
+        &["ls-files", "--others", "--exclude-standard", "--full-name"],
+    )
+    .wrap_err("could not get untracked files")?;
+
+    let mut changed = HashSet::new();
+    for name in names
+        .lines()
+        .chain(untracked.lines())
+        .filter(|name| !name.is_empty())
+    {
+        let path = toplevel.join(name);
+        changed.insert(
+            path.canonicalize()
+                .wrap_err_with(|| format!("could not canonicalize `{}`", path.display()))?,
+        );
+    }
+
+    Ok(Some(changed))
+}
+
+fn git(dir: &Path, args: &[&str]) -> Result<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .wrap_err("could not run git")?;
+
+    if !output.status.success() {
+        bail!(
+            "`git {}` failed ({}): {}",
+            args.join(" "),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        );
+    }
+
+    String::from_utf8(output.stdout).wrap_err("git's output was not valid UTF-8")
+}
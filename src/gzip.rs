@@ -0,0 +1,91 @@
+use color_eyre::eyre::{Result, WrapErr};
+use flate2::read::MultiGzDecoder;
+use std::io::Read;
+
+/// Every gzip file starts with these two bytes.
+pub const MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+pub fn is_gzip(bytes: &[u8]) -> bool {
+    bytes.starts_with(&MAGIC)
+}
+
+/// Decompress a gzip file for `--decompress`. Files can have several gzip
+/// members one after another (like `cat a.gz b.gz` makes), which decompress
+/// to their contents joined together, the same as `gunzip` does. Each
+/// member's CRC-32 and size are checked as we go.
+pub fn decompress(compressed: impl Read) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    MultiGzDecoder::new(compressed)
+        .read_to_end(&mut out)
+        .wrap_err("could not inflate")?;
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{Compression, GzBuilder};
+    use std::io::Write;
+
+    fn compress(builder: GzBuilder, data: &[u8]) -> Vec<u8> {
+        let mut encoder = builder.write(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn reads_every_member() {
+        let mut compressed = compress(GzBuilder::new(), b"fn a() {}\n");
+        compressed.extend(compress(GzBuilder::new(), b"fn b() {}\n"));
+
+        assert_eq!(
+            decompress(&compressed[..]).unwrap(),
+            b"fn a() {}\nfn b() {}\n"
+        );
+    }
+
+    #[test]
+    fn skips_optional_header_fields() {
+        // FEXTRA, FNAME, and FCOMMENT. flate2 doesn't write FHCRC.
+        let builder = GzBuilder::new()
+            .extra(b"extra".to_vec())
+            .filename("a.rs")
+            .comment("a comment");
+
+        assert_eq!(
+            decompress(&compress(builder, b"fn a() {}")[..]).unwrap(),
+            b"fn a() {}"
+        );
+    }
+
+    #[test]
+    fn skips_header_checksums() {
+        // a minimal header with FHCRC set and a CRC-16 of it, followed by
+        // the body and trailer of an ordinary gzip file.
+        let plain = compress(GzBuilder::new(), b"fn a() {}");
+        let mut header = plain[..10].to_vec();
+        header[3] |= 1 << 1;
+        let crc = {
+            let mut crc = flate2::Crc::new();
+            crc.update(&header);
+            crc.sum() as u16
+        };
+
+        let mut compressed = header;
+        compressed.extend(crc.to_le_bytes());
+        compressed.extend(&plain[10..]);
+
+        assert_eq!(decompress(&compressed[..]).unwrap(), b"fn a() {}");
+    }
+
+    #[test]
+    fn rejects_corrupted_data() {
+        let mut compressed = compress(GzBuilder::new(), b"fn a() {}");
+        // the CRC-32 is the first part of the trailer
+        let crc = compressed.len() - 8;
+        compressed[crc] ^= 0xff;
+
+        assert!(decompress(&compressed[..]).is_err());
+    }
+}
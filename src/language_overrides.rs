@@ -0,0 +1,75 @@
+use color_eyre::eyre::{bail, Result, WrapErr};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use std::path::Path;
+
+/// Per-path language choices that take precedence over extension-based
+/// detection. This is mostly for extensions that mean different things in
+/// different parts of a repo (e.g. `.h` being C in one directory and C++ in
+/// another.)
+///
+/// The file format is one override per line, in the same `{name}:{glob}`
+/// format as `--custom-language` (so `cpp:vendor/**/*.h,lib/*.h` works.)
+/// Blank lines and lines starting with `#` are ignored. When more than one
+/// line matches a path, the last one wins.
+#[derive(Debug)]
+pub struct LanguageOverrides {
+    globs: GlobSet,
+    languages: Vec<String>,
+}
+
+impl LanguageOverrides {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("could not read `{}`", path.display()))?;
+
+        Self::parse(&contents).wrap_err_with(|| format!("could not parse `{}`", path.display()))
+    }
+
+    fn parse(contents: &str) -> Result<Self> {
+        let mut builder = GlobSetBuilder::new();
+        let mut languages = Vec::new();
+
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (language, globs) = match line.split_once(':') {
+                Some(parts) => parts,
+                None => bail!(
+                    "line {}: expected an override in the format `{{name}}:{{glob}}`",
+                    i + 1
+                ),
+            };
+
+            for glob in globs.split(',') {
+                builder.add(
+                    Glob::new(glob.trim())
+                        .wrap_err_with(|| format!("line {}: invalid glob `{glob}`", i + 1))?,
+                );
+                languages.push(language.trim().to_string());
+            }
+        }
+
+        Ok(Self {
+            globs: builder
+                .build()
+                .wrap_err("could not build override matcher")?,
+            languages,
+        })
+    }
+
+    /// Get the overridden language for a path, if any.
+    pub fn language_for(&self, path: &Path) -> Option<&str> {
+        // the walker gives us paths like `./src/foo.h`, but people will write
+        // globs like `src/**/*.h`
+        let path = path.strip_prefix(".").unwrap_or(path);
+
+        self.globs
+            .matches(path)
+            .into_iter()
+            .max()
+            .map(|i| self.languages[i].as_str())
+    }
+}
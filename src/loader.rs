@@ -1,7 +1,9 @@
+use crate::manifest::{self, Manifest};
 use color_eyre::eyre::{bail, Result, WrapErr};
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use tree_sitter::Language;
 
 // TODO: Windows support should be possible, but I'm not sure how to do it right now
@@ -11,31 +13,90 @@ pub static DYLIB_EXTENSION: &str = "so";
 #[cfg(target_os = "macos")]
 pub static DYLIB_EXTENSION: &str = "dylib";
 
+/// How many directories deep `find_grammar` will look with
+/// `search_recursively`.
+pub const MAX_SEARCH_DEPTH: usize = 4;
+
 #[derive(Debug)]
 pub struct Loader {
     include: Vec<PathBuf>,
+    pinned: HashMap<String, PathBuf>,
+    manifest: Option<Manifest>,
+    recursive: bool,
+    libraries: Mutex<Option<Vec<PathBuf>>>,
     grammars: HashMap<String, libloading::Library>,
     languages: HashMap<String, Language>,
+    stamps: HashMap<String, String>,
+    mismatches: Vec<NameMismatch>,
+}
+
+/// A grammar we loaded under one name, from a library that looks like it's
+/// really a different grammar: it's named after another language, and
+/// exports that language's `tree_sitter_{name}` function too.
+#[derive(Debug)]
+pub struct NameMismatch {
+    pub requested: String,
+    pub library_name: String,
+    pub path: PathBuf,
+}
+
+impl std::fmt::Display for NameMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "we loaded `{}` from `{}`, but that library also exports `tree_sitter_{}`, so it may really be the `{}` grammar",
+            self.requested,
+            self.path.display(),
+            self.library_name,
+            self.library_name,
+        )
+    }
 }
 
 impl Loader {
     pub fn with_capacity(include: Vec<PathBuf>, size: usize) -> Self {
         Self {
             include,
+            pinned: HashMap::new(),
+            manifest: None,
+            recursive: false,
+            libraries: Mutex::new(None),
             grammars: HashMap::with_capacity(size),
             languages: HashMap::with_capacity(size),
+            stamps: HashMap::with_capacity(size),
+            mismatches: Vec::new(),
         }
     }
 
     pub fn preload(&mut self, language_name: String) -> Result<()> {
-        let symbol_name = format!("tree_sitter_{language_name}");
+        let manifested = self.manifested(&language_name).cloned();
+        let symbol_name = match &manifested {
+            Some(entry) => entry.symbol.clone(),
+            None => format!("tree_sitter_{language_name}"),
+        };
 
+        let mut found_at = None;
         let lib = match self.grammars.get(&language_name) {
             Some(grammar) => grammar,
             None => {
                 let grammar_path = self
                     .find_grammar(&language_name)
                     .wrap_err("could not find grammar")?;
+                found_at = Some(grammar_path.clone());
+
+                if let Some(entry) = &manifested {
+                    let hash = manifest::hash_file(&grammar_path)?;
+                    if hash != entry.hash {
+                        bail!(
+                            "`{}` has changed since it was added to the grammar manifest (its hash is {hash}, but we expected {})",
+                            grammar_path.display(),
+                            entry.hash
+                        );
+                    }
+                }
+
+                self.stamps
+                    .insert(language_name.clone(), stamp(&grammar_path)?);
 
                 let lib =
                     unsafe { libloading::Library::new(&grammar_path) }.wrap_err_with(|| {
@@ -49,36 +110,245 @@ impl Loader {
             }
         };
 
-        if let Entry::Vacant(entry) = self.languages.entry(language_name) {
+        // if the library is named after some other language and has that
+        // language's function, it's probably that grammar, whatever we were
+        // asked for.
+        let other = found_at.as_deref().and_then(library_name).filter(|other| {
+            *other != language_name
+                && unsafe {
+                    lib.get::<unsafe extern "C" fn() -> Language>(
+                        format!("tree_sitter_{other}").as_bytes(),
+                    )
+                }
+                .is_ok()
+        });
+
+        if let Entry::Vacant(entry) = self.languages.entry(language_name.clone()) {
             let language = unsafe {
-                let lang_fn: libloading::Symbol<unsafe extern "C" fn() -> Language> =
-                    lib.get(symbol_name.as_bytes()).wrap_err_with(|| {
-                        format!("could not load language function `{}`", symbol_name)
-                    })?;
+                let lang_fn: libloading::Symbol<unsafe extern "C" fn() -> Language> = match lib
+                    .get(symbol_name.as_bytes())
+                {
+                    Ok(lang_fn) => lang_fn,
+                    Err(err) => {
+                        let err = Err(err).wrap_err_with(|| {
+                            format!("could not load language function `{}`", symbol_name)
+                        });
+                        return match &other {
+                            Some(other) => err.wrap_err(format!(
+                                "the library seems to be the `{other}` grammar instead, since it has `tree_sitter_{other}`"
+                            )),
+                            None => err,
+                        };
+                    }
+                };
 
                 lang_fn()
             };
+
+            if let Some(expected) = &manifested {
+                if language.version() != expected.abi_version {
+                    bail!(
+                        "the grammar manifest says `{}` has ABI version {}, but the library we loaded has {}",
+                        entry.key(),
+                        expected.abi_version,
+                        language.version()
+                    );
+                }
+            }
+
             entry.insert(language);
         }
 
+        if let (Some(library_name), Some(path)) = (other, found_at) {
+            self.mismatches.push(NameMismatch {
+                requested: language_name,
+                library_name,
+                path,
+            });
+        }
+
         Ok(())
     }
 
+    /// Grammars that might not be the language we loaded them as.
+    pub fn mismatches(&self) -> &[NameMismatch] {
+        &self.mismatches
+    }
+
+    /// Load grammars listed in `manifest` from where it says they are (and
+    /// check they're what it says they are) instead of searching for them.
+    /// Anything not in the manifest is still searched for as usual.
+    pub fn use_manifest(&mut self, manifest: Manifest) {
+        self.manifest = Some(manifest);
+    }
+
+    /// The manifest's entry for `name`. Grammars pinned with `pin` win over
+    /// the manifest, so we ignore their entries entirely (including the
+    /// symbol and hash.)
+    fn manifested(&self, name: &str) -> Option<&manifest::Grammar> {
+        if self.pinned.contains_key(name) {
+            return None;
+        }
+
+        self.manifest.as_ref()?.grammars.get(name)
+    }
+
+    /// Always load `name` from `path` instead of searching for it.
+    pub fn pin(&mut self, name: String, path: PathBuf) {
+        self.pinned.insert(name, path);
+    }
+
     pub fn get(&self, language_name: &str) -> Option<Language> {
         self.languages.get(language_name).copied()
     }
 
-    fn find_grammar(&self, name: &str) -> Result<PathBuf> {
-        let search_name = PathBuf::from(format!("tree-sitter-{}.{}", name, DYLIB_EXTENSION));
+    /// The path, size, and modification time of the library we loaded
+    /// `language_name` from, so the parser cache can tell when a grammar
+    /// has been rebuilt (even if its ABI version stayed the same.)
+    pub fn stamp(&self, language_name: &str) -> Option<&str> {
+        self.stamps.get(language_name).map(String::as_str)
+    }
+
+    pub fn languages(&self) -> impl Iterator<Item = (&String, &Language)> {
+        self.languages.iter()
+    }
+
+    /// Look for a grammar in each include path. Besides our own flat
+    /// `tree-sitter-{name}.{ext}` (what `compile-grammar` makes), we also
+    /// check a couple of layouts package managers like to use.
+    pub fn find_grammar(&self, name: &str) -> Result<PathBuf> {
+        if let Some(path) = self.pinned.get(name) {
+            if !path.is_file() {
+                bail!(
+                    "the grammar for `{name}` is supposed to be at `{}`, but there's no file there",
+                    path.display()
+                );
+            }
+
+            return Ok(path.clone());
+        }
+
+        if let Some(entry) = self.manifested(name) {
+            if !entry.library.is_file() {
+                bail!(
+                    "the grammar manifest says `{name}` is at `{}`, but there's no file there",
+                    entry.library.display()
+                );
+            }
+
+            return Ok(entry.library.clone());
+        }
+
+        let layouts = [
+            PathBuf::from(format!("tree-sitter-{name}.{DYLIB_EXTENSION}")),
+            PathBuf::from(name).join(format!("{name}.{DYLIB_EXTENSION}")),
+            PathBuf::from(format!("tree-sitter-{name}")).join(format!("{name}.{DYLIB_EXTENSION}")),
+        ];
 
         for path in &self.include {
-            let candidate = path.join(&search_name);
-            tracing::debug!(name, ?candidate, "looking for grammar");
-            if candidate.exists() {
-                return Ok(candidate);
+            for layout in &layouts {
+                let candidate = path.join(layout);
+                tracing::debug!(name, ?candidate, "looking for grammar");
+                if candidate.exists() {
+                    return Ok(candidate);
+                }
             }
         }
 
-        bail!("could not find {search_name:?} in any included path")
+        if self.recursive {
+            let mut libraries = self
+                .libraries
+                .lock()
+                .expect("the library index lock should never be poisoned");
+            let libraries = libraries.get_or_insert_with(|| self.find_libraries());
+
+            if let Some(found) = libraries
+                .iter()
+                .find(|library| layouts.iter().any(|layout| library.ends_with(layout)))
+            {
+                tracing::info!(name, path = ?found, "found grammar in a subdirectory");
+                return Ok(found.clone());
+            }
+        }
+
+        bail!(
+            "could not find any of {} in any included path",
+            layouts
+                .iter()
+                .map(|layout| format!("{layout:?}"))
+                .collect::<Vec<String>>()
+                .join(", ")
+        )
     }
+
+    /// Look through subdirectories of the include paths (up to
+    /// `MAX_SEARCH_DEPTH` deep) for `find_grammar`, instead of only the
+    /// include paths themselves.
+    pub fn search_recursively(&mut self) {
+        self.recursive = true;
+    }
+
+    /// Every library under the include paths, in include path order. We only
+    /// do this once per loader, since walking a big tree is slow.
+    fn find_libraries(&self) -> Vec<PathBuf> {
+        let mut out = Vec::new();
+
+        for include in &self.include {
+            let mut found: Vec<PathBuf> = ignore::WalkBuilder::new(include)
+                .standard_filters(false)
+                .max_depth(Some(MAX_SEARCH_DEPTH))
+                .build()
+                .filter_map(|entry| match entry {
+                    Ok(entry) => Some(entry.into_path()),
+                    Err(err) => {
+                        tracing::debug!(?err, "skipping unreadable entry in grammar search");
+                        None
+                    }
+                })
+                .filter(|path| {
+                    path.is_file() && path.extension().map_or(false, |ext| ext == DYLIB_EXTENSION)
+                })
+                .collect();
+
+            // walk order depends on the filesystem, so sort to make sure we
+            // pick the same grammar every time.
+            found.sort();
+            out.extend(found);
+        }
+
+        out
+    }
+}
+
+/// The language a grammar library is named for, going by the layouts in
+/// `find_grammar` (so `tree-sitter-rust.so` and `rust/rust.so` are both
+/// `rust`.)
+fn library_name(path: &Path) -> Option<String> {
+    let stem = path.file_stem()?.to_str()?;
+    let stem = stem.strip_prefix("lib").unwrap_or(stem);
+    let stem = stem.strip_prefix("tree-sitter-").unwrap_or(stem);
+
+    if stem.is_empty() {
+        None
+    } else {
+        Some(stem.to_string())
+    }
+}
+
+/// See `Loader::stamp`.
+fn stamp(path: &Path) -> Result<String> {
+    let metadata = std::fs::metadata(path)
+        .wrap_err_with(|| format!("could not get metadata for `{}`", path.display()))?;
+    let modified = metadata
+        .modified()
+        .wrap_err_with(|| format!("could not get mtime for `{}`", path.display()))?
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+
+    Ok(format!(
+        "{}:{}:{}",
+        path.display(),
+        metadata.len(),
+        modified.as_nanos()
+    ))
 }
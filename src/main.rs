@@ -1,20 +1,198 @@
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches};
+use std::path::PathBuf;
 use tracing_error::ErrorLayer;
 use tracing_subscriber::fmt::format::FmtSpan;
 use tracing_subscriber::prelude::*;
 use tracing_subscriber::EnvFilter;
 
+mod baseline;
+mod compile_grammar;
+mod cozo_error;
+mod csv_output;
+mod duckdb_output;
+mod dump_ast;
 mod export;
+mod file_args;
+mod git;
+mod gzip;
+mod language_overrides;
 mod loader;
+mod manifest;
+mod merge;
+mod mermaid;
+mod neo4j;
+mod node_types;
+mod parser_cache;
+mod postgres_copy;
+mod project_config;
+mod query;
+mod schema;
+mod shebang;
+mod stats;
+mod timings;
+
+#[derive(Debug, clap::Parser)]
+#[command(version, long_version = long_version(), about)]
+struct Cli {
+    /// How should we format log messages? `json` is useful when running
+    /// tree-db as part of a bigger system that collects logs.
+    #[arg(
+        long,
+        global = true,
+        default_value = "human",
+        env = "TREE_DB_LOG_FORMAT"
+    )]
+    log_format: LogFormat,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+/// What we print for `--version`: our own version, plus the versions of the
+/// libraries that matter when a grammar or database won't load.
+fn long_version() -> String {
+    format!(
+        "{}\ntree-sitter {} (grammar ABI versions {} through {})\ncozo {}",
+        env!("CARGO_PKG_VERSION"),
+        env!("TREE_DB_TREE_SITTER_VERSION"),
+        tree_sitter::MIN_COMPATIBLE_LANGUAGE_VERSION,
+        tree_sitter::LANGUAGE_VERSION,
+        env!("TREE_DB_COZO_VERSION"),
+    )
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, clap::ValueEnum)]
+enum LogFormat {
+    /// Human-readable lines
+    Human,
+
+    /// One JSON object per line
+    Json,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum Command {
+    /// Parse source files and export their syntax trees as a database.
+    Export(export::ExporterConfig),
+
+    /// Run a CozoScript query against source files (or a previously exported
+    /// database) and print the results.
+    Query(query::QueryConfig),
+
+    /// Print a single file's tree as we'd export it (with ids, kinds, fields,
+    /// and byte ranges), for matching up exported rows with the source.
+    DumpAst(dump_ast::DumpAstConfig),
+
+    /// List every node kind and field name a grammar can produce, as JSON,
+    /// for knowing what to look for when writing queries.
+    NodeTypes(node_types::NodeTypesConfig),
+
+    /// Compile a tree-sitter grammar into a library we can load.
+    CompileGrammar(compile_grammar::CompileGrammarConfig),
+
+    /// Combine several exported SQLite databases into one, for when
+    /// different parts of a project were indexed separately.
+    Merge(merge::MergeConfig),
+}
+
+impl Command {
+    fn run(&self) -> color_eyre::Result<()> {
+        match self {
+            Self::Export(config) => config.run(),
+            Self::Query(config) => config.run(),
+            Self::DumpAst(config) => config.run(),
+            Self::NodeTypes(config) => config.run(),
+            Self::CompileGrammar(config) => config.run(),
+            Self::Merge(config) => config.run(),
+        }
+    }
+
+    fn usage_error(&self) -> Option<&'static str> {
+        match self {
+            Self::Export(config) => config.usage_error(),
+            Self::Query(config) => config.usage_error(),
+            Self::DumpAst(_) | Self::NodeTypes(_) | Self::CompileGrammar(_) | Self::Merge(_) => {
+                None
+            }
+        }
+    }
+}
+
+/// Parse our arguments, using the project config (if there is one) for
+/// defaults.
+fn parse_args() -> color_eyre::Result<Cli> {
+    let command = Cli::command().arg(
+        clap::Arg::new("config")
+            .long("config")
+            .global(true)
+            .value_parser(clap::value_parser!(PathBuf))
+            .help(format!(
+                "Where to read default flags from. Defaults to `{}` in the working directory, if it exists.",
+                project_config::FILE_NAME
+            )),
+    );
+
+    // we need to know which subcommand we're running (and where the config
+    // is) before we can set defaults, but a real parse could fail without
+    // those defaults, so look ahead leniently first.
+    let lookahead = command.clone().ignore_errors(true).get_matches();
+
+    let mut command = match project_config::ProjectConfig::find(
+        lookahead.get_one::<PathBuf>("config").map(PathBuf::as_path),
+    )? {
+        Some(config) => match lookahead.subcommand_name() {
+            Some(subcommand) => config.apply(command, subcommand)?,
+            None => command,
+        },
+        None => command,
+    };
+
+    let cli =
+        Cli::from_arg_matches(&command.clone().get_matches()).unwrap_or_else(|err| err.exit());
+    if let Some(message) = cli.command.usage_error() {
+        command
+            .error(clap::error::ErrorKind::ArgumentConflict, message)
+            .exit();
+    }
+
+    Ok(cli)
+}
 
 fn main() {
+    color_eyre::install().expect("could not initialize error handling");
+
+    let opts = match parse_args() {
+        Ok(opts) => opts,
+        Err(err) => {
+            eprintln!("{err:?}");
+            std::process::exit(1);
+        }
+    };
+
+    let (human, json) = match opts.log_format {
+        LogFormat::Human => (
+            Some(
+                tracing_subscriber::fmt::layer()
+                    .with_span_events(FmtSpan::NEW)
+                    .with_writer(std::io::stderr),
+            ),
+            None,
+        ),
+        LogFormat::Json => (
+            None,
+            Some(
+                tracing_subscriber::fmt::layer()
+                    .json()
+                    .with_span_events(FmtSpan::NEW)
+                    .with_writer(std::io::stderr),
+            ),
+        ),
+    };
+
     let subscriber = tracing_subscriber::Registry::default()
         .with(ErrorLayer::default())
-        .with(
-            tracing_subscriber::fmt::layer()
-                .with_span_events(FmtSpan::NEW)
-                .with_writer(std::io::stderr),
-        )
+        .with(human)
+        .with(json)
         .with(
             EnvFilter::try_from_default_env()
                 // TODO: default to `info` eventually
@@ -25,12 +203,12 @@ fn main() {
     tracing::subscriber::set_global_default(subscriber)
         .expect("could not initialize tracing subscribers");
 
-    color_eyre::install().expect("could not initialize error handling");
-
-    let opts = export::ExporterConfig::parse();
-
-    if let Err(err) = opts.run() {
+    if let Err(err) = opts.command.run() {
         eprintln!("{err:?}");
+
+        if err.downcast_ref::<export::MaxErrorsReached>().is_some() {
+            std::process::exit(3);
+        }
         std::process::exit(1);
     }
 }
@@ -0,0 +1,63 @@
+use color_eyre::eyre::{Result, WrapErr};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// A record of the grammars `compile-grammar --manifest` has built, so that
+/// builds can be audited (and checked) later.
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct Manifest {
+    pub grammars: BTreeMap<String, Grammar>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Grammar {
+    /// The grammar's source directory.
+    pub source: PathBuf,
+
+    /// The library we built.
+    pub library: PathBuf,
+
+    /// The function in `library` that returns the language.
+    pub symbol: String,
+
+    /// The tree-sitter ABI version the grammar was generated for.
+    pub abi_version: usize,
+
+    /// A 64-bit FNV-1a hash of `library`, in hex.
+    pub hash: String,
+}
+
+impl Manifest {
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = std::fs::File::open(path)
+            .wrap_err_with(|| format!("could not open `{}`", path.display()))?;
+
+        serde_json::from_reader(std::io::BufReader::new(file))
+            .wrap_err_with(|| format!("could not read `{}`", path.display()))
+    }
+
+    /// Load the manifest at `path`, or start a new one if there isn't one yet.
+    pub fn load_or_default(path: &Path) -> Result<Self> {
+        if path.exists() {
+            Self::load(path)
+        } else {
+            Ok(Self::default())
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut json =
+            serde_json::to_string_pretty(self).wrap_err("could not serialize manifest")?;
+        json.push('\n');
+
+        std::fs::write(path, json).wrap_err_with(|| format!("could not write `{}`", path.display()))
+    }
+}
+
+/// The hash we store for a library.
+pub fn hash_file(path: &Path) -> Result<String> {
+    let bytes =
+        std::fs::read(path).wrap_err_with(|| format!("could not read `{}`", path.display()))?;
+
+    Ok(format!("{:016x}", crate::export::fnv1a(&bytes) as u64))
+}
@@ -0,0 +1,103 @@
+use crate::cozo_error::IntoReport;
+use color_eyre::eyre::{bail, Result, WrapErr};
+use cozo::DbInstance;
+use serde_json::value::Value;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use tracing::instrument;
+
+#[derive(Debug, clap::Parser)]
+pub struct MergeConfig {
+    /// Where to write the merged SQLite database.
+    out: PathBuf,
+
+    /// The databases to merge, as exported by
+    /// `tree-db export --output cozo-sqlite`. They all have to have the same
+    /// schema (so, exported by the same version of tree-db with the same
+    /// options.) When more than one has a row with the same key (like the
+    /// same `(path, id)`), the last one wins.
+    #[arg(required = true, num_args = 1..)]
+    inputs: Vec<PathBuf>,
+}
+
+/// Every relation in a database, with a description of its columns.
+type Schema = BTreeMap<String, Vec<Vec<Value>>>;
+
+impl MergeConfig {
+    #[instrument]
+    pub fn run(&self) -> Result<()> {
+        let mut expected: Option<(&Path, Schema)> = None;
+        for input in &self.inputs {
+            let schema = Self::schema(input)
+                .wrap_err_with(|| format!("could not read the schema of `{}`", input.display()))?;
+
+            match &expected {
+                None => expected = Some((input, schema)),
+                Some((first, first_schema)) => {
+                    if schema != *first_schema {
+                        bail!(
+                            "`{}` has a different schema than `{}`. Were they exported with the same version of tree-db and the same options?",
+                            input.display(),
+                            first.display(),
+                        )
+                    }
+                }
+            }
+        }
+
+        let relations: Vec<String> = match expected {
+            Some((_, schema)) => schema.into_keys().collect(),
+            None => bail!("need at least one database to merge"),
+        };
+
+        let db = DbInstance::new("mem", "", "").into_report()?;
+
+        let (first, rest) = self
+            .inputs
+            .split_first()
+            .expect("we checked that there's at least one input above");
+
+        db.restore_backup(&first.display().to_string())
+            .into_report()?;
+
+        for input in rest {
+            tracing::debug!(?input, "merging");
+            db.import_from_backup(&input.display().to_string(), &relations)
+                .into_report()?;
+        }
+
+        db.backup_db(self.out.display().to_string()).into_report()?;
+
+        tracing::info!(out = ?self.out, inputs = self.inputs.len(), "merged databases");
+
+        Ok(())
+    }
+
+    fn schema(path: &Path) -> Result<Schema> {
+        if !path.exists() {
+            bail!("`{}` doesn't exist", path.display());
+        }
+
+        let db = DbInstance::new("sqlite", &path.display().to_string(), "").into_report()?;
+
+        let relations = db
+            .run_script("::relations", BTreeMap::new())
+            .into_report()?;
+
+        let mut schema = Schema::new();
+        for row in relations.rows {
+            let name = match row.first() {
+                Some(Value::String(name)) => name.clone(),
+                other => bail!("expected a relation name, but got {other:?}"),
+            };
+
+            let columns = db
+                .run_script(&format!("::columns {name}"), BTreeMap::new())
+                .into_report()?;
+
+            schema.insert(name, columns.rows);
+        }
+
+        Ok(schema)
+    }
+}
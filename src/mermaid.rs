@@ -0,0 +1,106 @@
+use crate::export::{ExporterConfig, OutputWriter};
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use cozo::NamedRows;
+use serde_json::value::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+/// Past this many nodes, Mermaid diagrams get too big to render or read, so
+/// we cut them off.
+pub const MAX_NODES: usize = 500;
+
+/// Render exported `nodes` and `edges` as a Mermaid `graph TD`, labelling
+/// nodes with their kind and edges with their field (if any.)
+pub fn render(nodes: &NamedRows, edges: &NamedRows) -> Result<String> {
+    let node_path = column(nodes, "path")?;
+    let node_id = column(nodes, "id")?;
+    let node_kind = column(nodes, "kind")?;
+
+    let edge_path = column(edges, "path")?;
+    let edge_parent = column(edges, "parent")?;
+    let edge_child = column(edges, "child")?;
+    let edge_field = column(edges, "field")?;
+
+    if nodes.rows.len() > MAX_NODES {
+        tracing::warn!(
+            "there are {} nodes, which is too many to draw. Only including the first {MAX_NODES}.",
+            nodes.rows.len()
+        );
+    }
+
+    let mut out = String::from("graph TD\n");
+
+    // node ids are only unique within a file, and Mermaid is picky about what
+    // can go in an id, so we number them ourselves.
+    let mut names: HashMap<(&Value, &Value), String> = HashMap::new();
+    for row in nodes.rows.iter().take(MAX_NODES) {
+        let name = format!("n{}", names.len());
+        writeln!(out, "    {name}[\"{}\"]", escape(&text(&row[node_kind])))
+            .expect("writing to a String can't fail");
+        names.insert((&row[node_path], &row[node_id]), name);
+    }
+
+    for row in &edges.rows {
+        let parent = names.get(&(&row[edge_path], &row[edge_parent]));
+        let child = names.get(&(&row[edge_path], &row[edge_child]));
+
+        if let (Some(parent), Some(child)) = (parent, child) {
+            match &row[edge_field] {
+                Value::Null => writeln!(out, "    {parent} --> {child}"),
+                field => writeln!(out, "    {parent} -->|{}| {child}", escape(&text(field))),
+            }
+            .expect("writing to a String can't fail");
+        }
+    }
+
+    Ok(out)
+}
+
+/// The `mermaid` output, written to stdout or -o/--output-path.
+pub struct MermaidWriter;
+
+impl OutputWriter for MermaidWriter {
+    fn relation_names(&self, _: &ExporterConfig) -> Vec<&'static str> {
+        vec!["nodes", "edges"]
+    }
+
+    fn write_relations(
+        &self,
+        relations: &BTreeMap<String, NamedRows>,
+        cfg: &ExporterConfig,
+    ) -> Result<()> {
+        cfg.write(&render(&relations["nodes"], &relations["edges"])?)
+            .wrap_err("could not write Mermaid diagram")
+    }
+}
+
+fn column(rows: &NamedRows, name: &str) -> Result<usize> {
+    rows.headers
+        .iter()
+        .position(|header| header == name)
+        .ok_or_else(|| eyre!("expected a `{name}` column, but it wasn't there"))
+}
+
+fn text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// Anonymous nodes have kinds like `"` and `|`, which would break Mermaid's
+/// syntax, so we use its entity codes instead.
+fn escape(label: &str) -> String {
+    let mut out = String::with_capacity(label.len());
+
+    for c in label.chars() {
+        match c {
+            '"' | '|' | '<' | '>' | '#' | '[' | ']' | '{' | '}' | '(' | ')' => {
+                write!(out, "#{};", c as u32).expect("writing to a String can't fail")
+            }
+            other => out.push(other),
+        }
+    }
+
+    out
+}
@@ -0,0 +1,238 @@
+use crate::export::{ExporterConfig, OutputWriter};
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use cozo::NamedRows;
+use serde_json::value::Value;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::Path;
+
+/// How many nodes or edges to create per `UNWIND` in Cypher output. One
+/// statement per node is very slow, but one statement for everything can
+/// run the database out of memory.
+pub const CYPHER_BATCH_SIZE: usize = 1000;
+
+/// The label we give every node, and the type we give every edge.
+pub const NODE_LABEL: &str = "Node";
+pub const EDGE_TYPE: &str = "CHILD";
+
+/// Write exported `nodes` and `edges` as `nodes.csv` and `edges.csv` in
+/// `dir`, with the headers `neo4j-admin database import` (and Memgraph's
+/// `LOAD CSV`) expect: `id:ID`, `kind`, `path`, and `:LABEL` for nodes, and
+/// `:START_ID`, `:END_ID`, `field`, and `:TYPE` for edges.
+///
+/// Node ids are only unique within a file, but graph databases need them
+/// to be unique across the whole import, so we use `{path}:{id}`.
+pub fn write_csvs(dir: &Path, nodes: &NamedRows, edges: &NamedRows) -> Result<()> {
+    let node_path = column(nodes, "path")?;
+    let node_id = column(nodes, "id")?;
+    let node_kind = column(nodes, "kind")?;
+
+    let edge_path = column(edges, "path")?;
+    let edge_parent = column(edges, "parent")?;
+    let edge_child = column(edges, "child")?;
+    let edge_field = column(edges, "field")?;
+
+    let nodes_path = dir.join("nodes.csv");
+    let mut writer = csv::Writer::from_path(&nodes_path)
+        .wrap_err_with(|| format!("could not create `{}`", nodes_path.display()))?;
+    writer
+        .write_record(["id:ID", "kind", "path", ":LABEL"])
+        .wrap_err("could not write header")?;
+    for row in &nodes.rows {
+        writer
+            .write_record([
+                id(&row[node_path], &row[node_id]),
+                text(&row[node_kind]),
+                text(&row[node_path]),
+                NODE_LABEL.to_string(),
+            ])
+            .wrap_err("could not write node")?;
+    }
+    writer.flush().wrap_err("could not write nodes")?;
+
+    let edges_path = dir.join("edges.csv");
+    let mut writer = csv::Writer::from_path(&edges_path)
+        .wrap_err_with(|| format!("could not create `{}`", edges_path.display()))?;
+    writer
+        .write_record([":START_ID", ":END_ID", "field", ":TYPE"])
+        .wrap_err("could not write header")?;
+    for row in &edges.rows {
+        writer
+            .write_record([
+                id(&row[edge_path], &row[edge_parent]),
+                id(&row[edge_path], &row[edge_child]),
+                // the importer skips empty fields, so edges without a field
+                // just don't get the property.
+                match &row[edge_field] {
+                    Value::Null => String::new(),
+                    field => text(field),
+                },
+                EDGE_TYPE.to_string(),
+            ])
+            .wrap_err("could not write edge")?;
+    }
+    writer.flush().wrap_err("could not write edges")
+}
+
+/// Render exported `nodes` and `edges` as Cypher statements for
+/// `cypher-shell`. Nodes get every column from `nodes` as a property (with
+/// the file-local id moved to `node_id`, since `id` is `{path}:{id}` like in
+/// `write_csvs`), and edges are `CHILD` relationships with their `field`.
+/// Both are created in batches with `UNWIND`.
+pub fn cypher(nodes: &NamedRows, edges: &NamedRows) -> Result<String> {
+    let node_path = column(nodes, "path")?;
+    let node_id = column(nodes, "id")?;
+
+    let edge_path = column(edges, "path")?;
+    let edge_parent = column(edges, "parent")?;
+    let edge_child = column(edges, "child")?;
+    let edge_field = column(edges, "field")?;
+
+    let mut out = String::new();
+
+    // without an index, matching up edges with their nodes is a full scan
+    // for every edge.
+    writeln!(
+        out,
+        "CREATE INDEX node_id IF NOT EXISTS FOR (n:{NODE_LABEL}) ON (n.id);"
+    )
+    .expect("writing to a String can't fail");
+
+    for batch in nodes.rows.chunks(CYPHER_BATCH_SIZE) {
+        let maps: Vec<String> = batch
+            .iter()
+            .map(|row| {
+                let mut properties = vec![format!(
+                    "id: {}",
+                    string(&id(&row[node_path], &row[node_id]))
+                )];
+                for (header, value) in nodes.headers.iter().zip(row) {
+                    let key = if header == "id" { "node_id" } else { header };
+                    properties.push(format!("{key}: {}", literal(value)));
+                }
+                format!("{{{}}}", properties.join(", "))
+            })
+            .collect();
+
+        writeln!(
+            out,
+            "UNWIND [{}] AS row\nCREATE (n:{NODE_LABEL}) SET n = row;",
+            maps.join(", ")
+        )
+        .expect("writing to a String can't fail");
+    }
+
+    for batch in edges.rows.chunks(CYPHER_BATCH_SIZE) {
+        let maps: Vec<String> = batch
+            .iter()
+            .map(|row| {
+                format!(
+                    "{{parent: {}, child: {}, field: {}}}",
+                    string(&id(&row[edge_path], &row[edge_parent])),
+                    string(&id(&row[edge_path], &row[edge_child])),
+                    literal(&row[edge_field]),
+                )
+            })
+            .collect();
+
+        writeln!(
+            out,
+            "UNWIND [{}] AS row\nMATCH (parent:{NODE_LABEL} {{id: row.parent}}), (child:{NODE_LABEL} {{id: row.child}})\nCREATE (parent)-[:{EDGE_TYPE} {{field: row.field}}]->(child);",
+            maps.join(", ")
+        )
+        .expect("writing to a String can't fail");
+    }
+
+    Ok(out)
+}
+
+/// The `cypher` output, written to stdout or -o/--output-path.
+pub struct CypherWriter;
+
+impl OutputWriter for CypherWriter {
+    fn relation_names(&self, _: &ExporterConfig) -> Vec<&'static str> {
+        vec!["nodes", "edges"]
+    }
+
+    fn write_relations(
+        &self,
+        relations: &BTreeMap<String, NamedRows>,
+        cfg: &ExporterConfig,
+    ) -> Result<()> {
+        cfg.write(&cypher(&relations["nodes"], &relations["edges"])?)
+            .wrap_err("could not write Cypher statements")
+    }
+}
+
+/// The `neo4j-csv` output, written into the -o/--output-path directory.
+pub struct Neo4jCsvWriter;
+
+impl OutputWriter for Neo4jCsvWriter {
+    fn relation_names(&self, _: &ExporterConfig) -> Vec<&'static str> {
+        vec!["nodes", "edges"]
+    }
+
+    fn write_relations(
+        &self,
+        relations: &BTreeMap<String, NamedRows>,
+        cfg: &ExporterConfig,
+    ) -> Result<()> {
+        write_csvs(
+            cfg.output_dir("Neo4j CSV")?,
+            &relations["nodes"],
+            &relations["edges"],
+        )
+    }
+}
+
+/// A JSON value as a Cypher literal. We only ever export scalars.
+fn literal(value: &Value) -> String {
+    match value {
+        Value::String(s) => string(s),
+        Value::Null => "null".to_string(),
+        other => other.to_string(),
+    }
+}
+
+/// A Cypher string literal. Source text can contain anything, so we escape
+/// quotes, backslashes, and every control character.
+fn string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('\'');
+
+    for c in s.chars() {
+        match c {
+            '\'' => out.push_str("\\'"),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                write!(out, "\\u{:04x}", c as u32).expect("writing to a String can't fail")
+            }
+            c => out.push(c),
+        }
+    }
+
+    out.push('\'');
+    out
+}
+
+/// A node's id, unique across the whole export.
+fn id(path: &Value, id: &Value) -> String {
+    format!("{}:{}", text(path), text(id))
+}
+
+fn column(rows: &NamedRows, name: &str) -> Result<usize> {
+    rows.headers
+        .iter()
+        .position(|header| header == name)
+        .ok_or_else(|| eyre!("expected a `{name}` column, but it wasn't there"))
+}
+
+fn text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
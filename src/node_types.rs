@@ -0,0 +1,30 @@
+use crate::export::SourceConfig;
+use color_eyre::eyre::{Result, WrapErr};
+use std::io::Write;
+use tracing::instrument;
+
+#[derive(Debug, clap::Parser)]
+pub struct NodeTypesConfig {
+    /// The language whose grammar to describe.
+    language: String,
+
+    /// Where to find the grammar (plus the usual export options, so kinds
+    /// come out the way they would in an export.)
+    #[command(flatten)]
+    source: SourceConfig,
+}
+
+impl NodeTypesConfig {
+    #[instrument]
+    pub fn run(&self) -> Result<()> {
+        let node_types = self
+            .source
+            .node_types(&self.language)
+            .wrap_err_with(|| format!("could not list node types for `{}`", self.language))?;
+
+        let mut stdout = std::io::stdout();
+        serde_json::to_writer_pretty(&mut stdout, &node_types)
+            .wrap_err("could not write to stdout")?;
+        writeln!(stdout).wrap_err("could not write to stdout")
+    }
+}
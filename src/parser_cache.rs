@@ -0,0 +1,159 @@
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use cozo::NamedRows;
+use serde_json::value::Value;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// An on-disk cache of each file's exported rows, so that re-running an
+/// export over files that haven't changed can skip parsing them.
+///
+/// Each entry lives in its own file, named after a hash of everything that
+/// could change the rows: the file's path and contents, the grammar (its
+/// name, library, and ABI version), the export options, and the tree-db
+/// version. That means we never have to invalidate anything; stale entries
+/// just stop being looked up (and `--clear-cache` cleans them out.)
+#[derive(Debug)]
+pub struct ParserCache {
+    dir: PathBuf,
+}
+
+/// What we keep for each file.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct CachedFile {
+    pub relations: BTreeMap<String, NamedRows>,
+    pub tree: Option<Value>,
+    pub error_nodes: usize,
+}
+
+impl ParserCache {
+    /// Open the cache in `dir`, or in the user's cache directory if that's
+    /// not given.
+    pub fn open(dir: Option<PathBuf>) -> Result<Self> {
+        let dir = match dir {
+            Some(dir) => dir,
+            None => Self::default_dir()?,
+        };
+
+        std::fs::create_dir_all(&dir)
+            .wrap_err_with(|| format!("could not create cache directory `{}`", dir.display()))?;
+
+        Ok(Self { dir })
+    }
+
+    /// `$XDG_CACHE_HOME/tree-db`, falling back to `~/.cache/tree-db`.
+    fn default_dir() -> Result<PathBuf> {
+        if let Some(cache_home) = std::env::var_os("XDG_CACHE_HOME") {
+            return Ok(PathBuf::from(cache_home).join("tree-db"));
+        }
+
+        match std::env::var_os("HOME") {
+            Some(home) => Ok(PathBuf::from(home).join(".cache").join("tree-db")),
+            None => Err(eyre!(
+                "could not find a cache directory. Set `XDG_CACHE_HOME` or `HOME`."
+            )),
+        }
+    }
+
+    /// Remove every entry (and any left half-written.)
+    pub fn clear(&self) -> Result<()> {
+        for entry in std::fs::read_dir(&self.dir).wrap_err("could not read cache directory")? {
+            let path = entry.wrap_err("could not read cache entry")?.path();
+            if path
+                .extension()
+                .map_or(false, |ext| ext == "json" || ext == "partial")
+            {
+                std::fs::remove_file(&path)
+                    .wrap_err_with(|| format!("could not remove `{}`", path.display()))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The key for a file's entry: a SHA-256 of the tree-db version, the
+    /// file's path and contents, the grammar's name, library (see
+    /// `Loader::stamp`), and ABI version, and `fingerprint` (the export
+    /// options that affect rows.) Each part is prefixed with its length, so
+    /// they can't run together.
+    pub fn key(
+        path: &Path,
+        source: &[u8],
+        language: &str,
+        library: &str,
+        abi_version: usize,
+        fingerprint: &[u8],
+    ) -> String {
+        let mut hasher = Sha256::new();
+        for part in [
+            env!("CARGO_PKG_VERSION").as_bytes(),
+            path.to_string_lossy().as_bytes(),
+            source,
+            language.as_bytes(),
+            library.as_bytes(),
+            abi_version.to_string().as_bytes(),
+            fingerprint,
+        ] {
+            hasher.update((part.len() as u64).to_le_bytes());
+            hasher.update(part);
+        }
+
+        format!("{:x}", hasher.finalize())
+    }
+
+    /// Look up an entry. Anything we can't read is treated as a miss, since
+    /// we can always re-parse.
+    pub fn get(&self, key: &str) -> Option<CachedFile> {
+        let path = self.path(key);
+        let file = std::fs::File::open(&path).ok()?;
+
+        match serde_json::from_reader(std::io::BufReader::new(file)) {
+            Ok(cached) => Some(cached),
+            Err(err) => {
+                tracing::debug!(?path, ?err, "ignoring unreadable cache entry");
+                None
+            }
+        }
+    }
+
+    /// Store an entry. We write it next to where it goes and then move it
+    /// into place, so another export reading the cache at the same time
+    /// (or after we're interrupted) never sees half an entry. The partial
+    /// file has our process id in its name, in case two exports are putting
+    /// the same entry at once.
+    pub fn put(&self, key: &str, cached: &CachedFile) -> Result<()> {
+        let path = self.path(key);
+        let partial = self
+            .dir
+            .join(format!("{key}.{}.partial", std::process::id()));
+
+        let written = std::fs::File::create(&partial)
+            .and_then(|file| {
+                let mut writer = std::io::BufWriter::new(file);
+                serde_json::to_writer(&mut writer, cached)?;
+                writer.flush()
+            })
+            .wrap_err_with(|| format!("could not write `{}`", partial.display()))
+            .and_then(|()| {
+                std::fs::rename(&partial, &path).wrap_err_with(|| {
+                    format!(
+                        "could not move `{}` to `{}`",
+                        partial.display(),
+                        path.display()
+                    )
+                })
+            });
+
+        if written.is_err() {
+            // nothing will ever look this up, so don't leave it lying around
+            let _ = std::fs::remove_file(&partial);
+        }
+
+        written
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{key}.json"))
+    }
+}
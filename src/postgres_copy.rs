@@ -0,0 +1,70 @@
+use crate::export::{ExporterConfig, OutputWriter};
+use color_eyre::eyre::{Result, WrapErr};
+use cozo::NamedRows;
+use serde_json::value::Value;
+use std::collections::BTreeMap;
+use std::io::Write;
+use tracing::instrument;
+
+/// Writes a SQL script for Postgres that creates a table for each relation
+/// and loads the data with `COPY ... FROM stdin`.
+pub struct PostgresCopyWriter;
+
+impl OutputWriter for PostgresCopyWriter {
+    #[instrument(skip(self, relations, cfg))]
+    fn write_relations(
+        &self,
+        relations: &BTreeMap<String, NamedRows>,
+        cfg: &ExporterConfig,
+    ) -> Result<()> {
+        let mut writer = cfg.writer().wrap_err("could not open output")?;
+        writeln!(writer, "BEGIN;\n")?;
+
+        for relation in cfg.source.schema() {
+            writeln!(writer, "{}", relation.to_postgres())?;
+
+            let data = relations
+                .get(relation.name)
+                .expect("all relations should be present in the export");
+
+            writeln!(
+                writer,
+                "COPY {} ({}) FROM stdin;",
+                relation.name,
+                data.headers.join(", ")
+            )?;
+            for row in &data.rows {
+                let fields: Vec<String> = row.iter().map(copy_field).collect();
+                writeln!(writer, "{}", fields.join("\t"))?;
+            }
+            writeln!(writer, "\\.\n")?;
+        }
+
+        writeln!(writer, "COMMIT;")?;
+        writer.flush().wrap_err("could not write output")
+    }
+}
+
+/// Format a value for Postgres' `COPY` text format, where tabs separate
+/// columns, newlines separate rows, and `\N` means null.
+fn copy_field(value: &Value) -> String {
+    match value {
+        Value::Null => "\\N".to_string(),
+        Value::Bool(true) => "t".to_string(),
+        Value::Bool(false) => "f".to_string(),
+        Value::String(s) => {
+            let mut out = String::with_capacity(s.len());
+            for c in s.chars() {
+                match c {
+                    '\\' => out.push_str("\\\\"),
+                    '\t' => out.push_str("\\t"),
+                    '\n' => out.push_str("\\n"),
+                    '\r' => out.push_str("\\r"),
+                    other => out.push(other),
+                }
+            }
+            out
+        }
+        other => other.to_string(),
+    }
+}
@@ -0,0 +1,207 @@
+use color_eyre::eyre::{bail, Result, WrapErr};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+pub static FILE_NAME: &str = ".tree-db.toml";
+
+/// Default flags for a project, read from `.tree-db.toml`. Anything given on
+/// the command line takes precedence.
+///
+/// Values can be strings, booleans, numbers, or arrays of those. Keys are
+/// the same as the long flags, with underscores instead of dashes
+/// (so `no_ignore = true` for `--no-ignore`.) Keys at the top level apply to
+/// every subcommand that has that flag, and keys in a table like `[export]`
+/// only apply to that subcommand:
+///
+/// ```toml
+/// include = ["grammars"]
+///
+/// [export]
+/// output = ["cozo-sqlite"]
+/// no_ignore = true
+/// ```
+#[derive(Debug, Default)]
+pub struct ProjectConfig {
+    path: PathBuf,
+    shared: BTreeMap<String, Vec<String>>,
+    commands: BTreeMap<String, BTreeMap<String, Vec<String>>>,
+}
+
+impl ProjectConfig {
+    /// Load the config at `explicit` if given, or `.tree-db.toml` in the
+    /// working directory if it exists.
+    pub fn find(explicit: Option<&Path>) -> Result<Option<Self>> {
+        let path = match explicit {
+            Some(path) => path.to_path_buf(),
+            None => {
+                let path = PathBuf::from(FILE_NAME);
+                if !path.exists() {
+                    return Ok(None);
+                }
+                path
+            }
+        };
+
+        let contents = std::fs::read_to_string(&path)
+            .wrap_err_with(|| format!("could not read `{}`", path.display()))?;
+
+        let mut config = Self::parse(&contents)
+            .wrap_err_with(|| format!("could not parse `{}`", path.display()))?;
+        config.path = path;
+
+        Ok(Some(config))
+    }
+
+    fn parse(contents: &str) -> Result<Self> {
+        let mut config = Self::default();
+
+        let document: toml::value::Table =
+            toml::from_str(contents).wrap_err("this isn't valid TOML")?;
+
+        for (key, value) in document {
+            match value {
+                toml::Value::Table(table) => {
+                    let keys = config.commands.entry(key.clone()).or_default();
+                    for (inner, value) in table {
+                        let values = flag_values(&value)
+                            .wrap_err_with(|| format!("could not read `{key}.{inner}`"))?;
+                        keys.insert(inner, values);
+                    }
+                }
+                value => {
+                    let values =
+                        flag_values(&value).wrap_err_with(|| format!("could not read `{key}`"))?;
+                    config.shared.insert(key, values);
+                }
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Use our values as the defaults for `subcommand`'s arguments.
+    pub fn apply(&self, mut command: clap::Command, subcommand: &str) -> Result<clap::Command> {
+        for name in self.commands.keys() {
+            if command.find_subcommand(name).is_none() {
+                bail!(
+                    "`{}` has settings for `{name}`, but there's no such subcommand",
+                    self.path.display()
+                );
+            }
+        }
+
+        for key in self.shared.keys() {
+            let known = command
+                .get_subcommands()
+                .any(|sub| sub.get_arguments().any(|arg| arg.get_id() == key.as_str()));
+            if !known {
+                bail!(
+                    "`{}` sets `{key}`, but no subcommand has that option",
+                    self.path.display()
+                );
+            }
+        }
+
+        let sub = match command.find_subcommand(subcommand) {
+            Some(sub) => sub,
+            None => return Ok(command),
+        };
+
+        let mut defaults: Vec<(String, Vec<String>)> = self
+            .shared
+            .iter()
+            .filter(|(key, _)| sub.get_arguments().any(|arg| arg.get_id() == key.as_str()))
+            .map(|(key, values)| (key.clone(), values.clone()))
+            .collect();
+
+        if let Some(keys) = self.commands.get(subcommand) {
+            for (key, values) in keys {
+                if !sub.get_arguments().any(|arg| arg.get_id() == key.as_str()) {
+                    bail!(
+                        "`{}` sets `{key}` for `{subcommand}`, but it doesn't have that option",
+                        self.path.display()
+                    );
+                }
+
+                defaults.retain(|(shared, _)| shared != key);
+                defaults.push((key.clone(), values.clone()));
+            }
+        }
+
+        if !defaults.is_empty() {
+            command = command.mut_subcommand(subcommand, |mut sub| {
+                for (key, values) in defaults {
+                    sub = sub.mut_arg(key, |arg| arg.default_values(values));
+                }
+                sub
+            });
+        }
+
+        Ok(command)
+    }
+}
+
+/// Turn a value (or an array of them) into the strings we'd have gotten on
+/// the command line.
+fn flag_values(value: &toml::Value) -> Result<Vec<String>> {
+    match value {
+        toml::Value::Array(items) => items.iter().map(flag_value).collect(),
+        value => Ok(vec![flag_value(value)?]),
+    }
+}
+
+fn flag_value(value: &toml::Value) -> Result<String> {
+    match value {
+        toml::Value::String(s) => Ok(s.clone()),
+        toml::Value::Boolean(b) => Ok(b.to_string()),
+        toml::Value::Integer(i) => Ok(i.to_string()),
+        toml::Value::Float(f) => Ok(f.to_string()),
+        other => bail!(
+            "expected a string, boolean, number, or array of those, but got a {}",
+            other.type_str()
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn both_quote_styles_can_hold_comment_and_array_characters() {
+        let config = ProjectConfig::parse(
+            r#"
+            include = ["a # b", 'c, d', "e\"f"] # a comment
+            [export]
+            csv_null = '#'
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.shared["include"], ["a # b", "c, d", "e\"f"]);
+        assert_eq!(config.commands["export"]["csv_null"], ["#"]);
+    }
+
+    #[test]
+    fn scalars_become_flag_values() {
+        let config = ProjectConfig::parse(
+            r#"
+            [export]
+            no_ignore = true
+            max_errors = 10
+            output = ["cozo-json", 'csv']
+            "#,
+        )
+        .unwrap();
+
+        let export = &config.commands["export"];
+        assert_eq!(export["no_ignore"], ["true"]);
+        assert_eq!(export["max_errors"], ["10"]);
+        assert_eq!(export["output"], ["cozo-json", "csv"]);
+    }
+
+    #[test]
+    fn nested_tables_are_rejected() {
+        assert!(ProjectConfig::parse("[export.csv]\ncsv_null = 'x'\n").is_err());
+    }
+}
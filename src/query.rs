@@ -0,0 +1,147 @@
+use crate::cozo_error::IntoReport;
+use crate::export::{RunContext, SourceConfig};
+use color_eyre::eyre::{bail, Result, WrapErr};
+use cozo::NamedRows;
+use serde_json::value::Value;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::PathBuf;
+use tracing::instrument;
+
+#[derive(Debug, clap::Parser)]
+pub struct QueryConfig {
+    /// The CozoScript query to run.
+    script: String,
+
+    /// Query a database previously exported with
+    /// `tree-db export --output cozo-sqlite` instead of parsing source files.
+    /// Options that only matter while parsing are ignored, and the ones that
+    /// pick files or grammars are rejected.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "file",
+            "language",
+            "no_language",
+            "custom_language",
+            "types_file",
+            "language_alias",
+            "language_overrides",
+            "include",
+            "grammar",
+            "grammar_manifest",
+            "root",
+            "git_changed",
+            "modified_since",
+            "sample",
+        ],
+    )]
+    db: Option<PathBuf>,
+
+    /// How should we print the results?
+    #[arg(long, default_value = "json")]
+    format: QueryFormat,
+
+    #[command(flatten)]
+    source: SourceConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, clap::ValueEnum)]
+pub enum QueryFormat {
+    /// The Cozo result, as JSON (headers and rows)
+    Json,
+
+    /// An aligned table, for humans
+    Table,
+}
+
+impl QueryConfig {
+    /// See `SourceConfig::usage_error`.
+    pub fn usage_error(&self) -> Option<&'static str> {
+        self.source.usage_error()
+    }
+
+    #[instrument]
+    pub fn run(&self) -> Result<()> {
+        let run = RunContext::default();
+
+        let db = match &self.db {
+            Some(path) => {
+                if !path.exists() {
+                    bail!("`{}` doesn't exist", path.display());
+                }
+
+                cozo::DbInstance::new("sqlite", &path.display().to_string(), "").into_report()?
+            }
+            None => self
+                .source
+                .slurp_all(&run)
+                .wrap_err("could not load source files to database")?,
+        };
+
+        let result = run
+            .timings()
+            .time("query", || db.run_script(&self.script, BTreeMap::new()))
+            .into_report()?;
+
+        let out = match self.format {
+            QueryFormat::Json => serde_json::to_string(&result.into_json())
+                .wrap_err("could not serialize query result")?,
+            QueryFormat::Table => Self::table(&result),
+        };
+
+        writeln!(std::io::stdout(), "{out}").wrap_err("could not write to stdout")?;
+
+        self.source.report_timings(&run);
+        self.source.report_stats(&run)?;
+
+        Ok(())
+    }
+
+    fn table(result: &NamedRows) -> String {
+        let cells: Vec<Vec<String>> = result
+            .rows
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|value| match value {
+                        Value::String(s) => s.clone(),
+                        other => other.to_string(),
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let mut widths: Vec<usize> = result.headers.iter().map(|h| h.chars().count()).collect();
+        for row in &cells {
+            for (i, cell) in row.iter().enumerate() {
+                if let Some(width) = widths.get_mut(i) {
+                    *width = (*width).max(cell.chars().count());
+                }
+            }
+        }
+
+        let format_row = |row: &[String]| -> String {
+            row.iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{cell:width$}", width = *width))
+                .collect::<Vec<String>>()
+                .join("  ")
+                .trim_end()
+                .to_string()
+        };
+
+        let mut out = Vec::with_capacity(cells.len() + 2);
+        out.push(format_row(&result.headers));
+        out.push(
+            widths
+                .iter()
+                .map(|width| "-".repeat(*width))
+                .collect::<Vec<String>>()
+                .join("  "),
+        );
+        out.extend(cells.iter().map(|row| format_row(row)));
+
+        out.join("\n")
+    }
+}
@@ -0,0 +1,298 @@
+use std::fmt::Write;
+
+/// The type of a column, as far as Cozo is concerned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnType {
+    Int,
+    NullableInt,
+    Bool,
+    String,
+    NullableString,
+}
+
+impl ColumnType {
+    pub fn cozo(&self) -> &'static str {
+        match self {
+            Self::Int => "Int",
+            Self::NullableInt => "Int?",
+            Self::Bool => "Bool",
+            Self::String => "String",
+            Self::NullableString => "String?",
+        }
+    }
+
+    pub fn sqlite(&self) -> &'static str {
+        match self {
+            Self::Int | Self::NullableInt => "INTEGER",
+            Self::Bool => "BOOLEAN",
+            Self::String | Self::NullableString => "TEXT",
+        }
+    }
+
+    pub fn postgres(&self) -> &'static str {
+        match self {
+            Self::Int | Self::NullableInt => "bigint",
+            Self::Bool => "boolean",
+            Self::String | Self::NullableString => "text",
+        }
+    }
+
+    pub fn duckdb(&self) -> &'static str {
+        match self {
+            Self::Int | Self::NullableInt => "BIGINT",
+            Self::Bool => "BOOLEAN",
+            Self::String | Self::NullableString => "VARCHAR",
+        }
+    }
+
+    pub fn is_nullable(&self) -> bool {
+        matches!(self, Self::NullableInt | Self::NullableString)
+    }
+
+    /// The pandas dtype to read this column as. The capitalized `Int64` can
+    /// hold nulls; `int64` can't.
+    pub fn pandas(&self) -> &'static str {
+        match self {
+            Self::Int => "int64",
+            Self::NullableInt => "Int64",
+            Self::Bool => "bool",
+            Self::String | Self::NullableString => "string",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Column {
+    pub name: &'static str,
+    pub kind: ColumnType,
+}
+
+impl Column {
+    pub const fn new(name: &'static str, kind: ColumnType) -> Self {
+        Self { name, kind }
+    }
+}
+
+/// One of the relations we export. Which relations (and which columns in
+/// them) exist depends on the export options, so this is the single place
+/// the schema, the headers on our rows, and any DDL we generate come from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relation {
+    pub name: &'static str,
+
+    /// Every column, in the order we build rows in.
+    columns: Vec<Column>,
+
+    /// The names of the columns that make up the key.
+    keys: Vec<&'static str>,
+
+    /// Other sets of columns to index in SQL databases, for joins. These
+    /// are usually the columns that point at rows in other relations, like
+    /// `child` in `edges`.
+    indexes: Vec<Vec<&'static str>>,
+}
+
+impl Relation {
+    pub fn new(name: &'static str, keys: Vec<Column>, values: Vec<Column>) -> Self {
+        Self {
+            name,
+            keys: keys.iter().map(|column| column.name).collect(),
+            columns: keys.into_iter().chain(values).collect(),
+            indexes: Vec::new(),
+        }
+    }
+
+    /// Index these columns too, when we create a table for this relation.
+    pub fn with_index(mut self, columns: &[&'static str]) -> Self {
+        self.indexes.push(columns.to_vec());
+        self
+    }
+
+    /// Key the relation by these columns instead, without changing the order
+    /// of the columns in our rows.
+    pub fn with_keys(mut self, keys: &[&'static str]) -> Self {
+        self.keys = keys.to_vec();
+        self
+    }
+
+    pub fn columns(&self) -> impl Iterator<Item = &Column> {
+        self.columns.iter()
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &Column> {
+        self.columns
+            .iter()
+            .filter(|column| self.keys.contains(&column.name))
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &Column> {
+        self.columns
+            .iter()
+            .filter(|column| !self.keys.contains(&column.name))
+    }
+
+    /// The column names, in the order we build rows in.
+    pub fn headers(&self) -> Vec<String> {
+        self.columns()
+            .map(|column| column.name.to_string())
+            .collect()
+    }
+
+    /// The `:create` statement for this relation.
+    pub fn to_cozo(&self) -> String {
+        let mut out = format!("{{:create {} {{\n", self.name);
+
+        for column in self.keys() {
+            writeln!(out, "    {}: {},", column.name, column.kind.cozo())
+                .expect("writing to a String can't fail");
+        }
+
+        if self.values().next().is_some() {
+            out.push_str("    =>\n");
+        }
+
+        for column in self.values() {
+            writeln!(out, "    {}: {},", column.name, column.kind.cozo())
+                .expect("writing to a String can't fail");
+        }
+
+        out.push_str("}}\n");
+        out
+    }
+
+    /// The `CREATE TABLE` statement for this relation in SQLite, plus its
+    /// indexes.
+    pub fn to_sqlite(&self) -> String {
+        self.create_table(ColumnType::sqlite) + &self.create_indexes()
+    }
+
+    /// The `CREATE TABLE` statement for this relation in Postgres, plus its
+    /// indexes. Like DuckDB, Postgres makes primary key columns `NOT NULL`,
+    /// so relations with a nullable key (like `edges`, whose `field` often
+    /// is null) get a plain index on their keys instead.
+    pub fn to_postgres(&self) -> String {
+        if self.keys().any(|column| column.kind.is_nullable()) {
+            Self::wrap_create_table(self.name, self.column_definitions(ColumnType::postgres))
+                + &self.keys_index()
+                + &self.create_indexes()
+        } else {
+            self.create_table(ColumnType::postgres) + &self.create_indexes()
+        }
+    }
+
+    /// The `CREATE TABLE` statement for this relation in DuckDB, plus an
+    /// index on the keys. DuckDB won't let primary key columns be null
+    /// (which `field` in `edges` often is), so we use a plain index instead.
+    pub fn to_duckdb(&self) -> String {
+        Self::wrap_create_table(self.name, self.column_definitions(ColumnType::duckdb))
+            + &self.keys_index()
+            + &self.create_indexes()
+    }
+
+    fn create_table(&self, sql_type: fn(&ColumnType) -> &'static str) -> String {
+        let mut lines = self.column_definitions(sql_type);
+        lines.push(format!("PRIMARY KEY ({})", self.key_names()));
+
+        Self::wrap_create_table(self.name, lines)
+    }
+
+    /// A plain index on the keys, for when we can't use a primary key.
+    fn keys_index(&self) -> String {
+        format!(
+            "CREATE INDEX {}_keys ON {} ({});\n",
+            self.name,
+            self.name,
+            self.key_names()
+        )
+    }
+
+    fn create_indexes(&self) -> String {
+        let mut out = String::new();
+
+        for columns in &self.indexes {
+            writeln!(
+                out,
+                "CREATE INDEX {}_{} ON {} ({});",
+                self.name,
+                columns.join("_"),
+                self.name,
+                columns.join(", ")
+            )
+            .expect("writing to a String can't fail");
+        }
+
+        out
+    }
+
+    fn column_definitions(&self, sql_type: fn(&ColumnType) -> &'static str) -> Vec<String> {
+        self.columns()
+            .map(|column| {
+                format!(
+                    "{} {}{}",
+                    column.name,
+                    sql_type(&column.kind),
+                    if column.kind.is_nullable() {
+                        ""
+                    } else {
+                        " NOT NULL"
+                    }
+                )
+            })
+            .collect()
+    }
+
+    fn key_names(&self) -> String {
+        self.keys()
+            .map(|column| column.name)
+            .collect::<Vec<&str>>()
+            .join(", ")
+    }
+
+    fn wrap_create_table(name: &str, lines: Vec<String>) -> String {
+        format!("CREATE TABLE {name} (\n    {}\n);\n", lines.join(",\n    "))
+    }
+}
+
+/// A script that creates all the given relations.
+pub fn to_cozo(relations: &[Relation]) -> String {
+    let mut out = String::new();
+
+    for relation in relations {
+        out.push_str(&relation.to_cozo());
+        out.push('\n');
+    }
+
+    out
+}
+
+/// `CREATE TABLE` statements for all the given relations.
+pub fn to_sqlite(relations: &[Relation]) -> String {
+    let mut out = String::new();
+
+    for relation in relations {
+        out.push_str(&relation.to_sqlite());
+        out.push('\n');
+    }
+
+    out
+}
+
+/// A JSON object of `{relation: {column: dtype}}`, suitable for passing
+/// each relation's entry to pandas' `read_csv(dtype=...)`.
+pub fn to_pandas(relations: &[Relation]) -> String {
+    let dtypes: serde_json::Map<String, serde_json::Value> = relations
+        .iter()
+        .map(|relation| {
+            (
+                relation.name.to_string(),
+                relation
+                    .columns()
+                    .map(|column| (column.name.to_string(), column.kind.pandas().into()))
+                    .collect::<serde_json::Map<String, serde_json::Value>>()
+                    .into(),
+            )
+        })
+        .collect();
+
+    format!("{}\n", serde_json::Value::Object(dtypes))
+}
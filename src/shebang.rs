@@ -0,0 +1,78 @@
+use color_eyre::eyre::{Result, WrapErr};
+use std::io::Read;
+use std::path::Path;
+
+/// How much of the file we'll read looking for a `#!` line. Anything longer
+/// than this is almost certainly not a shebang.
+const MAX_LINE: u64 = 256;
+
+/// Figure out the language of a script with no extension (like `bin/build`)
+/// from its `#!` line. The names we return are the same ones we use for file
+/// types, so they line up with grammar names.
+pub fn language_for(path: &Path) -> Result<Option<&'static str>> {
+    let mut start = Vec::with_capacity(MAX_LINE as usize);
+    std::fs::File::open(path)
+        .wrap_err_with(|| format!("could not open `{}`", path.display()))?
+        .take(MAX_LINE)
+        .read_to_end(&mut start)
+        .wrap_err_with(|| format!("could not read `{}`", path.display()))?;
+
+    let line = match start.strip_prefix(b"#!") {
+        Some(rest) => rest.split(|byte| *byte == b'\n').next().unwrap_or(rest),
+        None => return Ok(None),
+    };
+
+    Ok(std::str::from_utf8(line)
+        .ok()
+        .and_then(interpreter)
+        .and_then(language_for_interpreter))
+}
+
+/// Get the interpreter's name out of a shebang line, seeing through `env`
+/// (including `env -S` and any `VAR=value` settings.)
+fn interpreter(line: &str) -> Option<&str> {
+    let mut words = line.split_whitespace();
+    let program = basename(words.next()?);
+
+    if program != "env" {
+        return Some(program);
+    }
+
+    words
+        .find(|word| !word.starts_with('-') && !word.contains('='))
+        .map(basename)
+}
+
+fn basename(program: &str) -> &str {
+    program.rsplit('/').next().unwrap_or(program)
+}
+
+fn language_for_interpreter(interpreter: &str) -> Option<&'static str> {
+    // `python3.11` and `ruby2.7` should work the same as `python` and `ruby`
+    let name = interpreter.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+
+    let language = match name {
+        "awk" | "gawk" | "mawk" | "nawk" => "awk",
+        "bash" | "dash" | "ksh" | "sh" => "sh",
+        "bun" | "deno" | "node" | "nodejs" => "js",
+        "elixir" => "elixir",
+        "fish" => "fish",
+        "julia" => "julia",
+        "lua" | "luajit" => "lua",
+        "ocaml" => "ocaml",
+        "perl" => "perl",
+        "php" => "php",
+        "pypy" | "python" => "py",
+        "racket" => "racket",
+        "Rscript" => "r",
+        "ruby" => "ruby",
+        "runghc" | "runhaskell" => "haskell",
+        "swift" => "swift",
+        "tclsh" => "tcl",
+        "ts-node" => "ts",
+        "zsh" => "zsh",
+        _ => return None,
+    };
+
+    Some(language)
+}
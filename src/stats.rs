@@ -0,0 +1,56 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Counts of what happened during a run, for `--stats-json`.
+#[derive(Debug, Default)]
+pub struct Stats {
+    counts: Mutex<Counts>,
+}
+
+#[derive(Debug, Default, Clone, serde::Serialize)]
+pub struct Counts {
+    /// Files we found and decided to parse.
+    pub files_discovered: usize,
+
+    /// Files we actually parsed (so not counting ones from the cache or
+    /// skipped for any of the reasons in `files_skipped`.)
+    pub files_parsed: usize,
+    pub files_from_cache: usize,
+
+    /// Files we passed over, by why.
+    pub files_skipped: BTreeMap<&'static str, usize>,
+
+    pub files_with_errors: usize,
+    pub nodes: usize,
+    pub error_nodes: usize,
+    pub edges: usize,
+
+    /// Exported files per language.
+    pub languages: BTreeMap<String, usize>,
+}
+
+impl Stats {
+    pub fn update(&self, f: impl FnOnce(&mut Counts)) {
+        f(&mut self
+            .counts
+            .lock()
+            .expect("the stats lock should never be poisoned"))
+    }
+
+    pub fn skip(&self, reason: &'static str) {
+        self.update(|counts| *counts.files_skipped.entry(reason).or_default() += 1)
+    }
+
+    pub fn to_json(&self, elapsed: Duration) -> serde_json::Value {
+        let counts = self
+            .counts
+            .lock()
+            .expect("the stats lock should never be poisoned")
+            .clone();
+
+        let mut json = serde_json::to_value(counts).expect("counts should always serialize");
+        json["elapsed_seconds"] = elapsed.as_secs_f64().into();
+        json
+    }
+}
@@ -0,0 +1,51 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Wall-clock time spent in each phase of a run, for `--timing`.
+#[derive(Debug, Default)]
+pub struct Timings {
+    phases: Mutex<Vec<(&'static str, Duration)>>,
+}
+
+impl Timings {
+    /// Run `f`, recording how long it took as `phase`.
+    pub fn time<T>(&self, phase: &'static str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let out = f();
+        let elapsed = start.elapsed();
+
+        self.phases
+            .lock()
+            .expect("the timings lock should never be poisoned")
+            .push((phase, elapsed));
+
+        out
+    }
+
+    /// How long all the phases so far took together.
+    pub fn total(&self) -> Duration {
+        self.phases
+            .lock()
+            .expect("the timings lock should never be poisoned")
+            .iter()
+            .map(|(_, elapsed)| *elapsed)
+            .sum()
+    }
+
+    /// Log each phase, in the order they happened, plus a total. These go
+    /// through `tracing` like everything else, so they come out as JSON
+    /// with `--log-format json`.
+    pub fn log(&self) {
+        let phases = self
+            .phases
+            .lock()
+            .expect("the timings lock should never be poisoned");
+
+        for (phase, elapsed) in phases.iter() {
+            tracing::info!(phase, seconds = elapsed.as_secs_f64(), "timing");
+        }
+
+        let total: Duration = phases.iter().map(|(_, elapsed)| *elapsed).sum();
+        tracing::info!(phase = "total", seconds = total.as_secs_f64(), "timing");
+    }
+}